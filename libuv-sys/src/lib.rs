@@ -370,11 +370,12 @@ pub enum uv_process_flags {
     UV_PROCESS_DETACHED = (1 << 3),
     UV_PROCESS_WINDOWS_HIDE = (1 << 4),
 }
+pub use uv_process_flags::*;
 
 #[repr(C)]
 #[derive(Clone,Copy)]
 pub struct uv_stdio_container_t {
-    pub flags: uv_stdio_flags,
+    pub flags: c_int, // uv_stdio_flags, OR-ed together
     pub stream: *mut uv_stream_t, // UNION
 }
 
@@ -388,6 +389,7 @@ pub enum uv_stdio_flags {
     UV_READABLE_PIPE = 0x10,
     UV_WRITABLE_PIPE = 0x20,
 }
+pub use uv_stdio_flags::*;
 
 extern {
     pub fn uv_disable_stdio_inheritance();
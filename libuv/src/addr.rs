@@ -0,0 +1,382 @@
+//! Conversions between `std::net` addresses and the socket address structures libuv takes,
+//! along with libuv's address parsing and formatting functions.
+//!
+//! `SockAddr` owns a `sockaddr_storage`; it converts from any `std::net` socket address or raw
+//! `sockaddr_in`/`sockaddr_in6`, and back with `TryFrom`.  Ports, flow information and scope IDs
+//! all survive the round trip.
+
+use error::{check, Error, Result};
+use libc::{self, c_char, c_int, c_void, sa_family_t, size_t, sockaddr, sockaddr_in, sockaddr_in6,
+           sockaddr_storage};
+use libuv_sys;
+use std::convert::TryFrom;
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd",
+          target_os = "dragonfly", target_os = "openbsd", target_os = "netbsd"))]
+mod len {
+    use libc::{sockaddr_in, sockaddr_in6};
+    use std::mem;
+
+    pub fn set_v4(sin: &mut sockaddr_in) {
+        sin.sin_len = mem::size_of::<sockaddr_in>() as u8;
+    }
+
+    pub fn set_v6(sin6: &mut sockaddr_in6) {
+        sin6.sin6_len = mem::size_of::<sockaddr_in6>() as u8;
+    }
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "ios", target_os = "freebsd",
+              target_os = "dragonfly", target_os = "openbsd", target_os = "netbsd")))]
+mod len {
+    use libc::{sockaddr_in, sockaddr_in6};
+
+    pub fn set_v4(_: &mut sockaddr_in) {}
+
+    pub fn set_v6(_: &mut sockaddr_in6) {}
+}
+
+// Room for the longest IPv6 address with an embedded IPv4 address, and the terminator.
+const NAME_LEN: usize = 64;
+
+fn v4_to_raw(addr: &SocketAddrV4) -> sockaddr_in {
+    unsafe {
+        let mut sin: sockaddr_in = mem::zeroed();
+        len::set_v4(&mut sin);
+        sin.sin_family = libc::AF_INET as sa_family_t;
+        sin.sin_port = addr.port().to_be();
+        // The octets are already in network order, which is what `s_addr` holds.
+        sin.sin_addr.s_addr = u32::from_ne_bytes(addr.ip().octets());
+        sin
+    }
+}
+
+fn v6_to_raw(addr: &SocketAddrV6) -> sockaddr_in6 {
+    unsafe {
+        let mut sin6: sockaddr_in6 = mem::zeroed();
+        len::set_v6(&mut sin6);
+        sin6.sin6_family = libc::AF_INET6 as sa_family_t;
+        sin6.sin6_port = addr.port().to_be();
+        sin6.sin6_flowinfo = addr.flowinfo().to_be();
+        sin6.sin6_addr.s6_addr = mem::transmute::<[u8; 16], [u16; 8]>(addr.ip().octets());
+        sin6.sin6_scope_id = addr.scope_id();
+        sin6
+    }
+}
+
+fn v4_from_raw(sin: &sockaddr_in) -> SocketAddrV4 {
+    let ip = Ipv4Addr::from(sin.sin_addr.s_addr.to_ne_bytes());
+    SocketAddrV4::new(ip, u16::from_be(sin.sin_port))
+}
+
+fn v6_from_raw(sin6: &sockaddr_in6) -> SocketAddrV6 {
+    let octets = unsafe { mem::transmute::<[u16; 8], [u8; 16]>(sin6.sin6_addr.s6_addr) };
+    SocketAddrV6::new(Ipv6Addr::from(octets),
+                      u16::from_be(sin6.sin6_port),
+                      u32::from_be(sin6.sin6_flowinfo),
+                      sin6.sin6_scope_id)
+}
+
+/// A socket address in the form libuv and the operating system take it.
+#[derive(Clone,Copy)]
+pub struct SockAddr(sockaddr_storage);
+
+impl SockAddr {
+    /// Copies the address at `addr`, whose length is implied by its family.  Fails with
+    /// `EINVAL` for families other than IPv4 and IPv6.
+    ///
+    /// # Safety
+    ///
+    /// `addr` must point to a valid `sockaddr_in` or `sockaddr_in6`, or to a `sockaddr` of some
+    /// other family.
+    pub unsafe fn from_raw(addr: *const sockaddr) -> Result<SockAddr> {
+        match (*addr).sa_family as c_int {
+            libc::AF_INET => Ok(SockAddr::from(*(addr as *const sockaddr_in))),
+            libc::AF_INET6 => Ok(SockAddr::from(*(addr as *const sockaddr_in6))),
+            _ => Err(Error::EINVAL),
+        }
+    }
+
+    /// Returns the address family, `AF_INET` or `AF_INET6`.
+    pub fn family(&self) -> c_int {
+        self.0.ss_family as c_int
+    }
+
+    /// Returns the length of the address, as system calls expect it.
+    pub fn socklen(&self) -> c_int {
+        let len = match self.family() {
+            libc::AF_INET => mem::size_of::<sockaddr_in>(),
+            libc::AF_INET6 => mem::size_of::<sockaddr_in6>(),
+            _ => mem::size_of::<sockaddr_storage>(),
+        };
+        len as c_int
+    }
+
+    /// Returns a pointer to the address, for passing to libuv.
+    pub fn as_ptr(&self) -> *const sockaddr {
+        &self.0 as *const sockaddr_storage as *const sockaddr
+    }
+
+    /// Returns the underlying `sockaddr_storage`.
+    pub fn as_storage(&self) -> &sockaddr_storage {
+        &self.0
+    }
+
+    /// Returns the `std::net` equivalent.
+    pub fn to_socket_addr(&self) -> Result<SocketAddr> {
+        SocketAddr::try_from(*self)
+    }
+}
+
+impl From<SocketAddr> for SockAddr {
+    fn from(addr: SocketAddr) -> SockAddr {
+        match addr {
+            SocketAddr::V4(v4) => SockAddr::from(v4),
+            SocketAddr::V6(v6) => SockAddr::from(v6),
+        }
+    }
+}
+
+impl From<SocketAddrV4> for SockAddr {
+    fn from(addr: SocketAddrV4) -> SockAddr {
+        SockAddr::from(v4_to_raw(&addr))
+    }
+}
+
+impl From<SocketAddrV6> for SockAddr {
+    fn from(addr: SocketAddrV6) -> SockAddr {
+        SockAddr::from(v6_to_raw(&addr))
+    }
+}
+
+impl From<sockaddr_in> for SockAddr {
+    fn from(sin: sockaddr_in) -> SockAddr {
+        unsafe {
+            let mut storage: sockaddr_storage = mem::zeroed();
+            *(&mut storage as *mut sockaddr_storage as *mut sockaddr_in) = sin;
+            SockAddr(storage)
+        }
+    }
+}
+
+impl From<sockaddr_in6> for SockAddr {
+    fn from(sin6: sockaddr_in6) -> SockAddr {
+        unsafe {
+            let mut storage: sockaddr_storage = mem::zeroed();
+            *(&mut storage as *mut sockaddr_storage as *mut sockaddr_in6) = sin6;
+            SockAddr(storage)
+        }
+    }
+}
+
+impl From<sockaddr_storage> for SockAddr {
+    fn from(storage: sockaddr_storage) -> SockAddr {
+        SockAddr(storage)
+    }
+}
+
+impl From<SockAddr> for sockaddr_storage {
+    fn from(addr: SockAddr) -> sockaddr_storage {
+        addr.0
+    }
+}
+
+/// Fails with `EINVAL` unless the address is IPv4.
+impl TryFrom<SockAddr> for sockaddr_in {
+    type Error = Error;
+
+    fn try_from(addr: SockAddr) -> Result<sockaddr_in> {
+        if addr.family() != libc::AF_INET {
+            return Err(Error::EINVAL);
+        }
+        Ok(unsafe { *(addr.as_ptr() as *const sockaddr_in) })
+    }
+}
+
+/// Fails with `EINVAL` unless the address is IPv6.
+impl TryFrom<SockAddr> for sockaddr_in6 {
+    type Error = Error;
+
+    fn try_from(addr: SockAddr) -> Result<sockaddr_in6> {
+        if addr.family() != libc::AF_INET6 {
+            return Err(Error::EINVAL);
+        }
+        Ok(unsafe { *(addr.as_ptr() as *const sockaddr_in6) })
+    }
+}
+
+/// Fails with `EINVAL` for families other than IPv4 and IPv6.
+impl TryFrom<SockAddr> for SocketAddr {
+    type Error = Error;
+
+    fn try_from(addr: SockAddr) -> Result<SocketAddr> {
+        match addr.family() {
+            libc::AF_INET => sockaddr_in::try_from(addr).map(|sin| v4_from_raw(&sin).into()),
+            libc::AF_INET6 => sockaddr_in6::try_from(addr).map(|sin6| v6_from_raw(&sin6).into()),
+            _ => Err(Error::EINVAL),
+        }
+    }
+}
+
+impl PartialEq for SockAddr {
+    fn eq(&self, other: &SockAddr) -> bool {
+        match (self.to_socket_addr(), other.to_socket_addr()) {
+            (Ok(a), Ok(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Debug for SockAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.to_socket_addr() {
+            Ok(addr) => write!(f, "SockAddr({})", addr),
+            Err(_) => write!(f, "SockAddr(family {})", self.family()),
+        }
+    }
+}
+
+/// Parses an IPv4 address with `uv_ip4_addr`.
+pub fn ip4_addr(ip: &str, port: u16) -> Result<SocketAddrV4> {
+    let ip = CString::new(ip).map_err(|_| Error::EINVAL)?;
+    unsafe {
+        let mut sin: sockaddr_in = mem::zeroed();
+        check(libuv_sys::uv_ip4_addr(ip.as_ptr(), port as c_int, &mut sin))?;
+        Ok(v4_from_raw(&sin))
+    }
+}
+
+/// Parses an IPv6 address with `uv_ip6_addr`.  A zone suffix such as `%eth0` becomes the scope
+/// ID.
+pub fn ip6_addr(ip: &str, port: u16) -> Result<SocketAddrV6> {
+    let ip = CString::new(ip).map_err(|_| Error::EINVAL)?;
+    unsafe {
+        let mut sin6: sockaddr_in6 = mem::zeroed();
+        check(libuv_sys::uv_ip6_addr(ip.as_ptr(), port as c_int, &mut sin6))?;
+        Ok(v6_from_raw(&sin6))
+    }
+}
+
+fn name<F>(f: F) -> Result<String>
+    where F: FnOnce(*mut c_char, size_t) -> c_int
+{
+    let mut buf = [0 as c_char; NAME_LEN];
+    check(f(buf.as_mut_ptr(), buf.len() as size_t))?;
+    Ok(unsafe { CStr::from_ptr(buf.as_ptr()) }.to_string_lossy().into_owned())
+}
+
+/// Formats the IP part of an IPv4 socket address with `uv_ip4_name`.
+pub fn ip4_name(addr: &SocketAddrV4) -> Result<String> {
+    let sin = v4_to_raw(addr);
+    name(|buf, len| unsafe { libuv_sys::uv_ip4_name(&sin, buf, len) })
+}
+
+/// Formats the IP part of an IPv6 socket address with `uv_ip6_name`.
+pub fn ip6_name(addr: &SocketAddrV6) -> Result<String> {
+    let sin6 = v6_to_raw(addr);
+    name(|buf, len| unsafe { libuv_sys::uv_ip6_name(&sin6, buf, len) })
+}
+
+/// Formats an IP address with `uv_inet_ntop`.
+pub fn inet_ntop(ip: &IpAddr) -> Result<String> {
+    match *ip {
+        IpAddr::V4(ref v4) => {
+            let octets = v4.octets();
+            name(|buf, len| unsafe {
+                libuv_sys::uv_inet_ntop(libc::AF_INET, octets.as_ptr() as *const c_void, buf, len)
+            })
+        }
+        IpAddr::V6(ref v6) => {
+            let octets = v6.octets();
+            name(|buf, len| unsafe {
+                libuv_sys::uv_inet_ntop(libc::AF_INET6, octets.as_ptr() as *const c_void, buf, len)
+            })
+        }
+    }
+}
+
+/// Parses an IPv4 or IPv6 address with `uv_inet_pton`.
+pub fn inet_pton(src: &str) -> Result<IpAddr> {
+    let c_src = CString::new(src).map_err(|_| Error::EINVAL)?;
+    let mut octets = [0u8; 16];
+    unsafe {
+        if src.contains(':') {
+            let dst = octets.as_mut_ptr() as *mut c_void;
+            check(libuv_sys::uv_inet_pton(libc::AF_INET6, c_src.as_ptr(), dst))?;
+            Ok(IpAddr::V6(Ipv6Addr::from(octets)))
+        } else {
+            let dst = octets.as_mut_ptr() as *mut c_void;
+            check(libuv_sys::uv_inet_pton(libc::AF_INET, c_src.as_ptr(), dst))?;
+            Ok(IpAddr::V4(Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3])))
+        }
+    }
+}
+
+/// Converts an address filled in by libuv or the operating system.  Fails with `EINVAL` for
+/// families other than IPv4 and IPv6.
+pub(crate) unsafe fn from_raw(addr: *const sockaddr) -> Result<SocketAddr> {
+    SockAddr::from_raw(addr)?.to_socket_addr()
+}
+
+/// Calls a libuv function which fills in a socket address, such as `uv_tcp_getsockname`.
+pub(crate) fn fetch<F>(f: F) -> Result<SocketAddr>
+    where F: FnOnce(*mut sockaddr, *mut c_int) -> c_int
+{
+    unsafe {
+        let mut storage: sockaddr_storage = mem::zeroed();
+        let mut len = mem::size_of::<sockaddr_storage>() as c_int;
+        check(f(&mut storage as *mut sockaddr_storage as *mut sockaddr, &mut len))?;
+        SockAddr(storage).to_socket_addr()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(addr: SocketAddr) {
+        let raw = SockAddr::from(addr);
+        assert_eq!(SocketAddr::try_from(raw), Ok(addr));
+        assert_eq!(unsafe { from_raw(raw.as_ptr()) }, Ok(addr));
+    }
+
+    #[test]
+    fn round_trips() {
+        round_trip("127.0.0.1:8080".parse().unwrap());
+        round_trip("[::1]:443".parse().unwrap());
+        let link_local = "fe80::1".parse().unwrap();
+        round_trip(SocketAddr::V6(SocketAddrV6::new(link_local, 22, 7, 3)));
+    }
+
+    #[test]
+    fn port_is_network_order() {
+        let raw = SockAddr::from("10.1.2.3:258".parse::<SocketAddr>().unwrap());
+        assert_eq!(raw.socklen() as usize, mem::size_of::<sockaddr_in>());
+        let sin = sockaddr_in::try_from(raw).unwrap();
+        assert_eq!(sin.sin_port.to_ne_bytes(), [1, 2]);
+        assert!(sockaddr_in6::try_from(raw).is_err());
+    }
+
+    #[test]
+    fn libuv_parsing_and_formatting() {
+        let v4 = ip4_addr("192.168.0.1", 80).unwrap();
+        assert_eq!(v4, "192.168.0.1:80".parse().unwrap());
+        assert_eq!(ip4_name(&v4).unwrap(), "192.168.0.1");
+        assert_eq!(ip4_addr("not an address", 80), Err(Error::EINVAL));
+
+        let v6 = ip6_addr("2001:db8::1", 443).unwrap();
+        assert_eq!(*v6.ip(), "2001:db8::1".parse::<Ipv6Addr>().unwrap());
+        assert_eq!(ip6_name(&v6).unwrap(), "2001:db8::1");
+
+        for text in &["10.0.0.255", "::ffff:1.2.3.4", "fe80::1"] {
+            let ip = inet_pton(text).unwrap();
+            assert_eq!(ip, text.parse::<IpAddr>().unwrap());
+            assert_eq!(inet_ntop(&ip).unwrap(), *text);
+        }
+        assert!(inet_pton("1.2.3").is_err());
+    }
+}
@@ -0,0 +1,217 @@
+//! Routing libuv's own allocations through a Rust allocator.
+//!
+//! libuv allocates internally with `malloc`, which bypasses the program's `#[global_allocator]`.
+//! `replace_allocator` makes it use any `GlobalAlloc` instead.  C's `free` is not told the size
+//! of the block, so each allocation is prefixed with a header recording it.
+//!
+//! The allocator can only be replaced before libuv has allocated anything, since memory from
+//! `malloc` must not reach the new `free`.  Every function which lets libuv allocate fixes the
+//! allocator for good before calling it: creating a `Loop`, `process_title::init`,
+//! `dl::Library::open`, `sys_info::cpu_info`, `sys_info::interface_addresses` and the functions
+//! in `env`.  The rest of this crate's functions never reach libuv's allocator, so a
+//! replacement cannot race with them.
+
+use error::{check, Error, Result};
+use libc::{c_void, size_t};
+use libuv_sys;
+use std::alloc::{self, GlobalAlloc, Layout};
+use std::ptr;
+use std::sync::{Mutex, OnceLock};
+
+// Large enough for the size and aligned like `max_align_t`, so blocks suit any C type.
+const HEADER: usize = 16;
+
+#[derive(Clone,Copy,PartialEq,Eq)]
+enum State {
+    // libuv has not allocated anything yet.
+    Unused,
+    // libuv may have allocated with `malloc`.
+    Frozen,
+    // libuv uses `ALLOCATOR`.
+    Replaced,
+}
+
+static STATE: Mutex<State> = Mutex::new(State::Unused);
+static ALLOCATOR: OnceLock<&'static (dyn GlobalAlloc + Sync)> = OnceLock::new();
+
+/// Records that libuv may now hold memory from its current allocator.
+pub(crate) fn freeze() {
+    let mut state = STATE.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if *state == State::Unused {
+        *state = State::Frozen;
+    }
+}
+
+/// Makes libuv allocate through `allocator`.  Fails with `EBUSY` if libuv may already have
+/// allocated memory, or if the allocator has already been replaced.  Safe to call from any
+/// thread: a function which allocates through libuv either freezes the allocator first, or
+/// waits for the replacement to finish and then uses the new allocator throughout.
+pub fn replace_allocator(allocator: &'static (dyn GlobalAlloc + Sync)) -> Result<()> {
+    let mut state = STATE.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if *state != State::Unused {
+        return Err(Error::EBUSY);
+    }
+    if ALLOCATOR.set(allocator).is_err() {
+        return Err(Error::EBUSY);
+    }
+    unsafe { check(libuv_sys::uv_replace_allocator(malloc_cb, realloc_cb, calloc_cb, free_cb))? };
+    *state = State::Replaced;
+    Ok(())
+}
+
+// Forwards to whatever `#[global_allocator]` the program uses.
+struct RustAllocator;
+
+unsafe impl GlobalAlloc for RustAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        alloc::alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        alloc::alloc_zeroed(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        alloc::dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        alloc::realloc(ptr, layout, new_size)
+    }
+}
+
+/// Makes libuv allocate through the program's global allocator, with the same restrictions as
+/// `replace_allocator`.
+pub fn use_rust_allocator() -> Result<()> {
+    replace_allocator(&RustAllocator)
+}
+
+fn layout(size: usize) -> Option<Layout> {
+    let total = size.checked_add(HEADER)?;
+    Layout::from_size_align(total, HEADER).ok()
+}
+
+// Records the size in the header and returns the memory after it.
+unsafe fn finish(block: *mut u8, size: usize) -> *mut c_void {
+    if block.is_null() {
+        return ptr::null_mut();
+    }
+    (block as *mut usize).write(size);
+    block.add(HEADER) as *mut c_void
+}
+
+// Returns the start of the block holding `ptr`, and its layout.
+unsafe fn block(ptr: *mut c_void) -> (*mut u8, Layout) {
+    let block = (ptr as *mut u8).sub(HEADER);
+    let size = (block as *const usize).read();
+    (block, Layout::from_size_align_unchecked(size + HEADER, HEADER))
+}
+
+unsafe fn malloc_with(allocator: &dyn GlobalAlloc, size: usize) -> *mut c_void {
+    match layout(size) {
+        Some(layout) => finish(allocator.alloc(layout), size),
+        None => ptr::null_mut(),
+    }
+}
+
+unsafe fn calloc_with(allocator: &dyn GlobalAlloc, count: usize, size: usize) -> *mut c_void {
+    match count.checked_mul(size).and_then(|size| layout(size).map(|layout| (size, layout))) {
+        Some((size, layout)) => finish(allocator.alloc_zeroed(layout), size),
+        None => ptr::null_mut(),
+    }
+}
+
+unsafe fn realloc_with(allocator: &dyn GlobalAlloc,
+                       ptr: *mut c_void,
+                       size: usize)
+                       -> *mut c_void {
+    if ptr.is_null() {
+        return malloc_with(allocator, size);
+    }
+    let total = match layout(size) {
+        Some(layout) => layout.size(),
+        None => return ptr::null_mut(),
+    };
+    let (block, old) = block(ptr);
+    // On failure the original block is left alone, as C requires.
+    finish(allocator.realloc(block, old, total), size)
+}
+
+unsafe fn free_with(allocator: &dyn GlobalAlloc, ptr: *mut c_void) {
+    if !ptr.is_null() {
+        let (block, layout) = block(ptr);
+        allocator.dealloc(block, layout);
+    }
+}
+
+fn installed() -> &'static dyn GlobalAlloc {
+    // libuv only calls these after `replace_allocator` has stored the allocator.
+    *ALLOCATOR.get().expect("libuv allocator not set")
+}
+
+extern "C" fn malloc_cb(size: size_t) -> *mut c_void {
+    unsafe { malloc_with(installed(), size as usize) }
+}
+
+extern "C" fn realloc_cb(ptr: *mut c_void, size: size_t) -> *mut c_void {
+    unsafe { realloc_with(installed(), ptr, size as usize) }
+}
+
+extern "C" fn calloc_cb(count: size_t, size: size_t) -> *mut c_void {
+    unsafe { calloc_with(installed(), count as usize, size as usize) }
+}
+
+extern "C" fn free_cb(ptr: *mut c_void) {
+    unsafe { free_with(installed(), ptr) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use event_loop::Loop;
+    use std::slice;
+    use std::sync::atomic::{AtomicIsize, Ordering};
+
+    // Counts the bytes outstanding.
+    struct Counting(AtomicIsize);
+
+    unsafe impl GlobalAlloc for Counting {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            self.0.fetch_add(layout.size() as isize, Ordering::SeqCst);
+            alloc::alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            self.0.fetch_sub(layout.size() as isize, Ordering::SeqCst);
+            alloc::dealloc(ptr, layout)
+        }
+    }
+
+    #[test]
+    fn sizes_survive_round_trips() {
+        let counting = Counting(AtomicIsize::new(0));
+        unsafe {
+            let p = malloc_with(&counting, 10) as *mut u8;
+            p.write_bytes(7, 10);
+            let p = realloc_with(&counting, p as *mut c_void, 1000) as *mut u8;
+            assert_eq!(slice::from_raw_parts(p, 10), &[7; 10]);
+            assert_eq!(counting.0.load(Ordering::SeqCst), (1000 + HEADER) as isize);
+            free_with(&counting, p as *mut c_void);
+
+            let z = calloc_with(&counting, 4, 8) as *mut u8;
+            assert_eq!(slice::from_raw_parts(z, 32), &[0; 32]);
+            assert_eq!(z as usize % HEADER, 0);
+            free_with(&counting, z as *mut c_void);
+            free_with(&counting, ptr::null_mut());
+
+            assert!(calloc_with(&counting, usize::MAX, 2).is_null());
+        }
+        assert_eq!(counting.0.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn fixed_once_loops_exist() {
+        let _lp = Loop::new().unwrap();
+        assert_eq!(use_rust_allocator(), Err(Error::EBUSY));
+    }
+}
@@ -0,0 +1,515 @@
+//! A pre-forking TCP server, in the style of Node's `cluster` module.
+//!
+//! The master process binds a listening socket and starts a number of worker processes running
+//! the same program.  Each worker receives the socket over an IPC pipe and accepts connections
+//! from it directly, so the operating system spreads connections across the workers.  Workers
+//! which crash are restarted, after a delay which doubles with each consecutive crash.  When
+//! the master receives `SIGTERM`, or `Master::shutdown` is called, it forwards `SIGTERM` to
+//! every worker and waits for them to exit; workers should watch for it with a `Signal`, stop
+//! accepting and exit once their connections are finished.
+//!
+//! A program typically branches on `worker_id` at startup, calling `Master::start` when it is
+//! `None` and `Worker::start` otherwise.
+
+use error::{Error, Result};
+use event_loop::Loop;
+use handle::CallbackCell;
+use ipc::ReceivedHandle;
+use libc;
+use pipe::Pipe;
+use process::{Process, ProcessOptions, StdioContainer};
+use signal::Signal;
+use std::cell::RefCell;
+use std::env;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::rc::{Rc, Weak};
+use std::thread;
+use std::time::Duration;
+use stream::Stream;
+use tcp::Tcp;
+use time::LoopTime;
+use timer::Timer;
+
+/// The environment variable which tells a worker its ID.
+pub const WORKER_ENV: &str = "LIBUV_RS_CLUSTER_WORKER";
+
+// The worker's file descriptor for the IPC pipe to the master.
+const CHANNEL_FD: usize = 3;
+
+const LISTEN_MESSAGE: &[u8] = b"listen";
+
+// A crashed worker is restarted after this delay, doubled for each consecutive crash up to the
+// maximum.  A worker which stays up for the maximum delay no longer counts as crashing.
+const RESTART_DELAY: Duration = Duration::from_millis(100);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(30);
+
+fn restart_delay(crashes: u32) -> Duration {
+    (RESTART_DELAY * (1 << crashes.saturating_sub(1).min(16))).min(MAX_RESTART_DELAY)
+}
+
+/// Returns this process's worker ID if it was started by a `Master`.
+pub fn worker_id() -> Option<usize> {
+    env::var(WORKER_ENV).ok().and_then(|id| id.parse().ok())
+}
+
+/// Something which happened to the master's workers.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum ClusterEvent {
+    /// A worker was started, initially or as a replacement.
+    Started {
+        /// The worker's ID, from zero to one less than the number of workers.
+        id: usize,
+        /// The worker's process ID.
+        pid: i32,
+    },
+    /// A worker could not be started.  Its slot stays empty.
+    SpawnFailed {
+        /// The worker's ID.
+        id: usize,
+        /// The reason.
+        error: Error,
+    },
+    /// A worker exited.
+    Exited {
+        /// The worker's ID.
+        id: usize,
+        /// The worker's process ID.
+        pid: i32,
+        /// Its exit status.
+        exit_status: i64,
+        /// The signal which killed it, or zero.
+        term_signal: i32,
+    },
+    /// Every worker has exited and the master has closed its handles.
+    Drained,
+}
+
+/// Settings for a `Master`.
+pub struct ClusterOptions {
+    program: PathBuf,
+    args: Vec<OsString>,
+    workers: usize,
+    restart: bool,
+}
+
+impl ClusterOptions {
+    /// Runs this program, with the arguments it was started with, in one worker per CPU.
+    pub fn new() -> ClusterOptions {
+        ClusterOptions {
+            program: env::current_exe().unwrap_or_default(),
+            args: env::args_os().skip(1).collect(),
+            workers: thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+            restart: true,
+        }
+    }
+
+    /// Sets the program the workers run.
+    pub fn program(&mut self, program: &Path) -> &mut ClusterOptions {
+        self.program = program.to_owned();
+        self
+    }
+
+    /// Sets the workers' arguments, not including the program name.
+    pub fn args<I, S>(&mut self, args: I) -> &mut ClusterOptions
+        where I: IntoIterator<Item = S>,
+              S: Into<OsString>
+    {
+        self.args = args.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Sets the number of workers.
+    pub fn workers(&mut self, workers: usize) -> &mut ClusterOptions {
+        self.workers = workers;
+        self
+    }
+
+    /// Controls whether workers which exit with a non-zero status or are killed by a signal are
+    /// restarted.  The first restart waits 100 milliseconds, and each consecutive crash of the
+    /// same worker doubles the wait, up to 30 seconds.  Workers which exit successfully are
+    /// never restarted.
+    pub fn restart(&mut self, restart: bool) -> &mut ClusterOptions {
+        self.restart = restart;
+        self
+    }
+}
+
+impl Default for ClusterOptions {
+    fn default() -> ClusterOptions {
+        ClusterOptions::new()
+    }
+}
+
+struct WorkerSlot {
+    process: Process,
+    // Kept open so that the worker does not see end of file on its channel.
+    _channel: Pipe,
+    started: LoopTime,
+    // Set when the socket could not be sent.  The worker has been killed, and its exit is only
+    // waited for.
+    abandoned: bool,
+}
+
+struct MasterState {
+    lp: Loop,
+    options: ClusterOptions,
+    listener: Option<Rc<Tcp>>,
+    signal: Option<Signal>,
+    workers: Vec<Option<WorkerSlot>>,
+    // Each worker's consecutive crashes, and the timer for its pending restart.
+    crashes: Vec<u32>,
+    restarts: Vec<Option<Timer>>,
+    draining: bool,
+}
+
+struct Inner {
+    state: RefCell<MasterState>,
+    events: CallbackCell<Box<dyn FnMut(ClusterEvent)>>,
+}
+
+impl Inner {
+    fn emit(&self, event: ClusterEvent) {
+        self.events.with(|cb| cb(event));
+    }
+}
+
+/// The master process of a cluster.  Workers are only supervised while it is alive.
+pub struct Master {
+    inner: Rc<Inner>,
+}
+
+impl Master {
+    /// Starts the workers and hands each of them `listener`, which should be bound but need not
+    /// be listening.  `events` is told about workers starting and exiting.
+    pub fn start<F>(lp: &Loop, listener: Tcp, options: ClusterOptions, events: F) -> Result<Master>
+        where F: FnMut(ClusterEvent) + 'static
+    {
+        let workers = options.workers;
+        let inner = Rc::new(Inner {
+            state: RefCell::new(MasterState {
+                lp: lp.clone(),
+                options,
+                listener: Some(Rc::new(listener)),
+                signal: Some(Signal::new(lp)?),
+                workers: (0..workers).map(|_| None).collect(),
+                crashes: vec![0; workers],
+                restarts: (0..workers).map(|_| None).collect(),
+                draining: false,
+            }),
+            events: CallbackCell::new(),
+        });
+        inner.events.set(Box::new(events));
+
+        let weak = Rc::downgrade(&inner);
+        inner.state.borrow().signal.as_ref().unwrap().start(libc::SIGTERM, move |_| {
+                if let Some(inner) = weak.upgrade() {
+                    shutdown(&inner);
+                }
+            })?;
+        for id in 0..workers {
+            if let Err(err) = spawn_worker(&inner, id) {
+                shutdown(&inner);
+                return Err(err);
+            }
+        }
+        Ok(Master { inner })
+    }
+
+    /// Stops restarting workers, sends each of them `SIGTERM` and closes the master's copy of
+    /// the listening socket.  `ClusterEvent::Drained` follows once they have all exited.
+    pub fn shutdown(&self) {
+        shutdown(&self.inner);
+    }
+
+    /// Returns the ID and process ID of each running worker.
+    pub fn workers(&self) -> Vec<(usize, i32)> {
+        let state = self.inner.state.borrow();
+        state.workers
+            .iter()
+            .enumerate()
+            .filter_map(|(id, slot)| slot.as_ref().map(|slot| (id, slot)))
+            .filter(|&(_, slot)| !slot.abandoned)
+            .map(|(id, slot)| (id, slot.process.pid()))
+            .collect()
+    }
+}
+
+fn spawn_worker(inner: &Rc<Inner>, id: usize) -> Result<()> {
+    let pid = {
+        let mut state = inner.state.borrow_mut();
+        let listener = state.listener.clone().ok_or(Error::ECANCELED)?;
+        let channel = Pipe::new(&state.lp, true)?;
+        let process = {
+            let mut options = ProcessOptions::new(&state.options.program);
+            options.args(&state.options.args)
+                .env(WORKER_ENV, id.to_string())
+                .stdio(0, StdioContainer::InheritFd(0))
+                .stdio(1, StdioContainer::InheritFd(1))
+                .stdio(2, StdioContainer::InheritFd(2))
+                .stdio(CHANNEL_FD,
+                       StdioContainer::CreatePipe {
+                           pipe: &channel,
+                           readable: true,
+                           writable: true,
+                       });
+            let weak = Rc::downgrade(inner);
+            Process::spawn(&state.lp, &options, move |exit_status, term_signal| {
+                    worker_exited(&weak, id, exit_status, term_signal);
+                })?
+        };
+        // If the worker dies before reading the socket, its exit is handled as usual.  If the
+        // socket cannot be sent at all, the worker is useless and is killed, but stays in its
+        // slot until it exits so that it is reaped.
+        let sent = channel.write_handle(LISTEN_MESSAGE.to_vec(), listener, |_| ());
+        if sent.is_err() {
+            let _ = process.kill(libc::SIGKILL);
+        }
+        let pid = process.pid();
+        let started = state.lp.now();
+        state.workers[id] = Some(WorkerSlot {
+            process,
+            _channel: channel,
+            started,
+            abandoned: sent.is_err(),
+        });
+        sent.map(|_| pid)?
+    };
+    inner.emit(ClusterEvent::Started { id, pid });
+    Ok(())
+}
+
+fn worker_exited(inner: &Weak<Inner>, id: usize, exit_status: i64, term_signal: i32) {
+    let inner = match inner.upgrade() {
+        Some(inner) => inner,
+        None => return,
+    };
+    let (slot, restart) = {
+        let mut state = inner.state.borrow_mut();
+        let slot = match state.workers[id].take() {
+            Some(slot) => slot,
+            None => return,
+        };
+        let crashed = exit_status != 0 || term_signal != 0;
+        if !crashed || state.lp.now() - slot.started >= MAX_RESTART_DELAY {
+            state.crashes[id] = 0;
+        }
+        if crashed {
+            state.crashes[id] += 1;
+        }
+        let restart = crashed && state.options.restart && !state.draining && !slot.abandoned;
+        (slot, restart)
+    };
+    // An abandoned worker was reported as failing to start rather than as started.
+    if !slot.abandoned {
+        inner.emit(ClusterEvent::Exited {
+            id,
+            pid: slot.process.pid(),
+            exit_status,
+            term_signal,
+        });
+    }
+    if restart {
+        schedule_restart(&inner, id);
+    }
+    finish_if_idle(&inner);
+}
+
+fn schedule_restart(inner: &Rc<Inner>, id: usize) {
+    let started = {
+        let mut state = inner.state.borrow_mut();
+        let delay = restart_delay(state.crashes[id]);
+        let weak = Rc::downgrade(inner);
+        let timer = Timer::new(&state.lp);
+        timer.and_then(|timer| {
+            timer.start(delay, Duration::from_secs(0), move || {
+                    if let Some(inner) = weak.upgrade() {
+                        restart(&inner, id);
+                    }
+                })?;
+            state.restarts[id] = Some(timer);
+            Ok(())
+        })
+    };
+    if let Err(error) = started {
+        inner.emit(ClusterEvent::SpawnFailed { id, error });
+    }
+}
+
+fn restart(inner: &Rc<Inner>, id: usize) {
+    let timer = inner.state.borrow_mut().restarts[id].take();
+    drop(timer);
+    if let Err(error) = spawn_worker(inner, id) {
+        inner.emit(ClusterEvent::SpawnFailed { id, error });
+    }
+    finish_if_idle(inner);
+}
+
+fn shutdown(inner: &Rc<Inner>) {
+    {
+        let mut state = inner.state.borrow_mut();
+        if state.draining {
+            return;
+        }
+        state.draining = true;
+        state.listener = None;
+        // Pending restarts are cancelled.
+        for timer in state.restarts.iter_mut() {
+            timer.take();
+        }
+        for slot in state.workers.iter().filter_map(|slot| slot.as_ref()) {
+            let _ = slot.process.kill(libc::SIGTERM);
+        }
+    }
+    finish_if_idle(inner);
+}
+
+fn finish_if_idle(inner: &Rc<Inner>) {
+    {
+        let mut state = inner.state.borrow_mut();
+        if state.signal.is_none() || state.workers.iter().any(|slot| slot.is_some()) ||
+           state.restarts.iter().any(|timer| timer.is_some()) {
+            return;
+        }
+        state.listener = None;
+        state.signal = None;
+    }
+    inner.emit(ClusterEvent::Drained);
+}
+
+/// A worker's connection to its master.
+pub struct Worker {
+    _channel: Rc<Pipe>,
+}
+
+impl Worker {
+    /// Opens the channel to the master and waits for the listening socket, which is passed to
+    /// `cb`.  Fails with `EINVAL` if this process is not a worker.
+    pub fn start<F>(lp: &Loop, cb: F) -> Result<Worker>
+        where F: FnOnce(Result<Tcp>) + 'static
+    {
+        if worker_id().is_none() {
+            return Err(Error::EINVAL);
+        }
+        let channel = Rc::new(Pipe::open_ipc(lp, CHANNEL_FD as i32)?);
+        let weak = Rc::downgrade(&channel);
+        let mut cb = Some(cb);
+        channel.read_start_ipc(move |read| {
+                let result = match read {
+                    Ok(read) => {
+                        match read.handles.into_iter().next() {
+                            Some(Ok(ReceivedHandle::Tcp(tcp))) => Ok(tcp),
+                            Some(Ok(_)) => Err(Error::EINVAL),
+                            Some(Err(err)) => Err(err),
+                            None => return,
+                        }
+                    }
+                    Err(err) => Err(err),
+                };
+                if let Some(channel) = weak.upgrade() {
+                    let _ = channel.read_stop();
+                }
+                if let Some(cb) = cb.take() {
+                    cb(result);
+                }
+            })?;
+        Ok(Worker { _channel: channel })
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use event_loop::RunMode;
+
+    fn run_master(script: &str, shutdown: bool) -> Vec<ClusterEvent> {
+        let lp = Loop::new().unwrap();
+        let listener = Tcp::bind(&lp, &"127.0.0.1:0".parse().unwrap()).unwrap();
+        let mut options = ClusterOptions::new();
+        options.program(Path::new("sh")).args(["-c", script]).workers(2);
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let events2 = events.clone();
+        let master = Master::start(&lp, listener, options, move |event| {
+                events2.borrow_mut().push(event);
+            })
+            .unwrap();
+        assert_eq!(master.workers().len(), 2);
+        if shutdown {
+            master.shutdown();
+        }
+        lp.run(RunMode::Default);
+        assert!(master.workers().is_empty());
+        let events = events.borrow().clone();
+        events
+    }
+
+    fn count<F: Fn(&ClusterEvent) -> bool>(events: &[ClusterEvent], f: F) -> usize {
+        events.iter().filter(|event| f(event)).count()
+    }
+
+    #[test]
+    fn clean_exits_are_not_restarted() {
+        let events = run_master("exit 0", false);
+        assert_eq!(count(&events, |e| matches!(*e, ClusterEvent::Started { .. })), 2);
+        assert_eq!(count(&events, |e| {
+                       matches!(*e, ClusterEvent::Exited { exit_status: 0, term_signal: 0, .. })
+                   }),
+                   2);
+        assert_eq!(events.last(), Some(&ClusterEvent::Drained));
+    }
+
+    #[test]
+    fn shutdown_terminates_workers() {
+        let events = run_master("sleep 10", true);
+        assert_eq!(count(&events, |e| {
+                       matches!(*e, ClusterEvent::Exited { term_signal: libc::SIGTERM, .. })
+                   }),
+                   2);
+        assert_eq!(events.last(), Some(&ClusterEvent::Drained));
+    }
+
+    #[test]
+    fn restart_delay_doubles() {
+        assert_eq!(restart_delay(1), Duration::from_millis(100));
+        assert_eq!(restart_delay(3), Duration::from_millis(400));
+        assert_eq!(restart_delay(100), MAX_RESTART_DELAY);
+    }
+
+    #[test]
+    fn crashes_are_restarted_with_backoff() {
+        let lp = Loop::new().unwrap();
+        let listener = Tcp::bind(&lp, &"127.0.0.1:0".parse().unwrap()).unwrap();
+        let mut options = ClusterOptions::new();
+        options.program(Path::new("sh")).args(["-c", "exit 1"]).workers(1);
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let (events2, lp2) = (events.clone(), lp.clone());
+        let master = Master::start(&lp, listener, options, move |event| {
+                events2.borrow_mut().push((lp2.now(), event));
+            })
+            .unwrap();
+        let started = |events: &[(LoopTime, ClusterEvent)]| {
+            events.iter().filter(|&&(_, e)| matches!(e, ClusterEvent::Started { .. })).count()
+        };
+        while started(&events.borrow()) < 3 {
+            lp.run(RunMode::Once);
+        }
+        master.shutdown();
+        lp.run(RunMode::Default);
+
+        let events = events.borrow();
+        let times: Vec<LoopTime> = events.iter().map(|&(time, _)| time).collect();
+        // Started, Exited, Started after 100ms, Exited, Started after 200ms, Exited, Drained.
+        assert_eq!(events.len(), 7);
+        assert!(times[2] - times[1] >= Duration::from_millis(100));
+        assert!(times[4] - times[3] >= Duration::from_millis(200));
+        assert_eq!(events[6].1, ClusterEvent::Drained);
+    }
+
+    #[test]
+    fn worker_requires_master() {
+        if worker_id().is_none() {
+            let lp = Loop::new().unwrap();
+            assert_eq!(Worker::start(&lp, |_| ()).err(), Some(Error::EINVAL));
+        }
+    }
+}
@@ -0,0 +1,166 @@
+//! Loading shared libraries at run time.
+
+use allocator;
+use libc::c_void;
+use libuv_sys::{self, uv_lib_t};
+use std::cell::UnsafeCell;
+use std::error;
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::Deref;
+use std::path::Path;
+use std::ptr;
+use std::result;
+use util::path_to_cstring;
+
+/// An error from the dynamic loader.  Unlike the rest of libuv, it reports a message rather
+/// than an error code.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct DlError {
+    message: String,
+}
+
+impl DlError {
+    fn new<S: Into<String>>(message: S) -> DlError {
+        DlError { message: message.into() }
+    }
+
+    /// Returns the loader's description of the error.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for DlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl error::Error for DlError {}
+
+/// The result of a loader operation.
+pub type DlResult<T> = result::Result<T, DlError>;
+
+unsafe fn last_error(lib: *const uv_lib_t) -> DlError {
+    DlError::new(CStr::from_ptr(libuv_sys::uv_dlerror(lib)).to_string_lossy().into_owned())
+}
+
+/// A loaded shared library, which is unloaded when dropped.
+pub struct Library {
+    // `uv_dlsym` records its error message in the struct.
+    lib: UnsafeCell<uv_lib_t>,
+}
+
+// The loader itself is thread-safe; only the error message needs exclusive access, which
+// `UnsafeCell` already confines to one thread.
+unsafe impl Send for Library {}
+
+impl Library {
+    /// Loads the library at `path`.  A bare file name is searched for in the usual places.
+    pub fn open(path: &Path) -> DlResult<Library> {
+        let path = path_to_cstring(path).map_err(|_| DlError::new("path contains a NUL byte"))?;
+        // libuv keeps the messages of failed lookups until the library is closed.
+        allocator::freeze();
+        unsafe {
+            let mut lib: uv_lib_t = mem::zeroed();
+            if libuv_sys::uv_dlopen(path.as_ptr(), &mut lib) != 0 {
+                let err = last_error(&lib);
+                // Frees the error message.
+                libuv_sys::uv_dlclose(&mut lib);
+                return Err(err);
+            }
+            Ok(Library { lib: UnsafeCell::new(lib) })
+        }
+    }
+
+    /// Looks up the symbol `name`, which must be a function or static of type `T`; for a
+    /// function, `T` is the corresponding `extern "C" fn` type, and for a static, a pointer to
+    /// it.  The symbol cannot outlive the library.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `T` is not pointer-sized, which no function pointer or pointer can be.
+    ///
+    /// # Safety
+    ///
+    /// Nothing checks that `T` is the symbol's real type; using it as the wrong type is
+    /// undefined behaviour.  The lifetime only binds the `Symbol` itself: `*symbol` copies the
+    /// function pointer or pointer out, and the copy must not be used once the library is
+    /// dropped.
+    pub unsafe fn get<T: Copy>(&self, name: &str) -> DlResult<Symbol<'_, T>> {
+        assert_eq!(mem::size_of::<T>(),
+                   mem::size_of::<*mut c_void>(),
+                   "symbols must be pointer-sized");
+        let name = CString::new(name).map_err(|_| DlError::new("name contains a NUL byte"))?;
+        let mut ptr: *mut c_void = ptr::null_mut();
+        if libuv_sys::uv_dlsym(self.lib.get(), name.as_ptr(), &mut ptr) != 0 {
+            return Err(last_error(self.lib.get()));
+        }
+        if ptr.is_null() {
+            return Err(DlError::new(format!("{} is null", name.to_string_lossy())));
+        }
+        Ok(Symbol {
+            value: mem::transmute_copy(&ptr),
+            _lib: PhantomData,
+        })
+    }
+
+    /// Returns the underlying `uv_lib_t`.
+    pub fn as_raw(&self) -> *mut uv_lib_t {
+        self.lib.get()
+    }
+}
+
+impl Drop for Library {
+    fn drop(&mut self) {
+        unsafe { libuv_sys::uv_dlclose(self.lib.get()) }
+    }
+}
+
+/// A symbol looked up in a `Library`.  Derefs to the function pointer or pointer.
+pub struct Symbol<'lib, T> {
+    value: T,
+    _lib: PhantomData<&'lib Library>,
+}
+
+impl<'lib, T> Deref for Symbol<'lib, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<'lib, T> fmt::Debug for Symbol<'lib, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // `get` only creates pointer-sized symbols.
+        let ptr = unsafe { *(&self.value as *const T as *const *const c_void) };
+        write!(f, "Symbol({:p})", ptr)
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use libc::{c_char, size_t};
+
+    #[test]
+    fn call_libc_function() {
+        let lib = Library::open(Path::new("libc.so.6")).unwrap();
+        unsafe {
+            let strlen = lib.get::<unsafe extern "C" fn(*const c_char) -> size_t>("strlen")
+                .unwrap();
+            assert_eq!(strlen(b"hello\0".as_ptr() as *const c_char), 5);
+            assert!(lib.get::<unsafe extern "C" fn()>("libuv_rs_missing").is_err());
+        }
+    }
+
+    #[test]
+    fn missing_library_reports_message() {
+        let err = Library::open(Path::new("libuv-rs-missing.so")).err().unwrap();
+        assert!(err.message().contains("libuv-rs-missing.so"));
+    }
+}
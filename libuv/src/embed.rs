@@ -0,0 +1,139 @@
+//! Driving a loop from a `mio::Poll`, for hosting libuv-based code in a mio-based program
+//! without a thread of its own.  Enabled by the `mio` feature.
+//!
+//! The same approach works with any epoll or kqueue instance: watch `Loop::backend_fd` for
+//! readability, wait no longer than `Loop::backend_timeout`, and call `Loop::poll_embedded`.
+
+use event_loop::Loop;
+use mio::unix::SourceFd;
+use mio::{Interest, Registry, Token};
+use std::io;
+use std::os::unix::io::RawFd;
+use std::time::Duration;
+
+/// A loop whose backend file descriptor is registered with a `mio::Registry`.
+pub struct MioAdapter {
+    lp: Loop,
+    fd: RawFd,
+    token: Token,
+}
+
+impl MioAdapter {
+    /// Registers the loop's backend file descriptor with `registry` under `token`.
+    pub fn register(lp: &Loop, registry: &Registry, token: Token) -> io::Result<MioAdapter> {
+        let fd = lp.backend_fd()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "loop has no backend fd"))?;
+        registry.register(&mut SourceFd(&fd), token, Interest::READABLE)?;
+        Ok(MioAdapter {
+            lp: lp.clone(),
+            fd,
+            token,
+        })
+    }
+
+    /// Returns the token events for the loop arrive with.
+    pub fn token(&self) -> Token {
+        self.token
+    }
+
+    /// Returns the timeout to pass to `mio::Poll::poll`, so that the loop's timers fire on time.
+    /// Combine it with any timeout of your own by taking the shorter.
+    pub fn timeout(&self) -> Option<Duration> {
+        self.lp.backend_timeout()
+    }
+
+    /// Processes whatever the loop has pending.  Call it after every `poll` which returned an
+    /// event for `token` or timed out.  Returns true if the loop is still alive.
+    pub fn dispatch(&self) -> bool {
+        self.lp.poll_embedded()
+    }
+
+    /// Removes the registration.
+    pub fn deregister(&self, registry: &Registry) -> io::Result<()> {
+        registry.deregister(&mut SourceFd(&self.fd))
+    }
+
+    /// Returns the loop.
+    pub fn get_loop(&self) -> &Loop {
+        &self.lp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mio::{Events, Poll};
+    use std::cell::{Cell, RefCell};
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::rc::Rc;
+    use std::thread;
+    use stream::Stream;
+    use tcp::Tcp;
+    use timer::Timer;
+
+    #[test]
+    fn timer_fires_under_mio() {
+        let lp = Loop::new().unwrap();
+        let mut poll = Poll::new().unwrap();
+        let adapter = MioAdapter::register(&lp, poll.registry(), Token(7)).unwrap();
+        let timer = Timer::new(&lp).unwrap();
+        let fired = Rc::new(Cell::new(false));
+        let fired2 = fired.clone();
+        timer.start(Duration::from_millis(5), Duration::from_secs(0), move || fired2.set(true))
+            .unwrap();
+
+        let mut events = Events::with_capacity(8);
+        while !fired.get() {
+            poll.poll(&mut events, adapter.timeout()).unwrap();
+            adapter.dispatch();
+        }
+        adapter.deregister(poll.registry()).unwrap();
+        drop(timer);
+        assert!(!adapter.dispatch());
+    }
+
+    #[test]
+    fn echoes_socket_under_mio() {
+        let lp = Loop::new().unwrap();
+        let mut poll = Poll::new().unwrap();
+        let adapter = MioAdapter::register(&lp, poll.registry(), Token(7)).unwrap();
+        let listener = Tcp::bind(&lp, &"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.sockname().unwrap();
+        // The connection only starts reading, and later writing, from inside callbacks.
+        let conn = Rc::new(RefCell::new(None));
+        let conn2 = conn.clone();
+        listener.listen(1, move |tcp| {
+                let tcp = Rc::new(tcp.unwrap());
+                let weak = Rc::downgrade(&tcp);
+                tcp.read_start(move |data| {
+                        let tcp = weak.upgrade().unwrap();
+                        match data {
+                            Ok(data) => tcp.write(data.to_vec(), |res| res.unwrap()).unwrap(),
+                            Err(_) => tcp.read_stop().unwrap(),
+                        }
+                    })
+                    .unwrap();
+                *conn2.borrow_mut() = Some(tcp);
+            })
+            .unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(b"hello").unwrap();
+            let mut buf = [0; 5];
+            stream.read_exact(&mut buf).unwrap();
+            buf
+        });
+
+        let mut events = Events::with_capacity(8);
+        while !client.is_finished() {
+            poll.poll(&mut events, adapter.timeout()).unwrap();
+            adapter.dispatch();
+        }
+        assert_eq!(&client.join().unwrap(), b"hello");
+        adapter.deregister(poll.registry()).unwrap();
+        drop(listener);
+        conn.borrow_mut().take();
+        adapter.dispatch();
+    }
+}
@@ -0,0 +1,149 @@
+//! The current process's environment, as libuv sees it.
+//!
+//! These follow libuv's rules, which differ from `std::env` in places: `homedir` consults the
+//! password database when `HOME` is unset, and trailing separators are removed from paths.
+
+use allocator;
+use error::{check, Error, Result};
+use libc::{c_char, c_long, size_t};
+use libuv_sys::{self, uv_passwd_t};
+use std::ffi::CStr;
+use std::mem;
+use std::path::{Path, PathBuf};
+use util::{bytes_to_path, fetch_bytes, path_to_cstring};
+
+// Each function here freezes the allocator first: libuv allocates while converting paths on
+// Windows, and while reading the password database elsewhere.
+
+/// Returns the path of the running executable.
+pub fn exepath() -> Result<PathBuf> {
+    // `uv_exepath` truncates rather than reporting `UV_ENOBUFS`, so retry with a larger buffer
+    // until the result leaves room to spare.
+    allocator::freeze();
+    let mut buf: Vec<u8> = Vec::with_capacity(256);
+    loop {
+        let mut size = buf.capacity() as size_t;
+        unsafe {
+            check(libuv_sys::uv_exepath(buf.as_mut_ptr() as *mut c_char, &mut size))?;
+        }
+        if (size as usize) + 1 < buf.capacity() {
+            unsafe {
+                buf.set_len(size as usize);
+            }
+            return Ok(bytes_to_path(buf));
+        }
+        let capacity = buf.capacity();
+        buf.reserve(capacity * 2);
+    }
+}
+
+/// Returns the current working directory.
+pub fn cwd() -> Result<PathBuf> {
+    allocator::freeze();
+    fetch_bytes(|buf, size| unsafe { libuv_sys::uv_cwd(buf, size) }).map(bytes_to_path)
+}
+
+/// Changes the current working directory.
+pub fn chdir(dir: &Path) -> Result<()> {
+    let dir = path_to_cstring(dir)?;
+    allocator::freeze();
+    unsafe { check(libuv_sys::uv_chdir(dir.as_ptr())).map(|_| ()) }
+}
+
+/// Returns the current user's home directory: `HOME` (or `USERPROFILE` on Windows) if it is set,
+/// and otherwise the directory recorded for the user.
+pub fn homedir() -> Result<PathBuf> {
+    allocator::freeze();
+    fetch_bytes(|buf, size| unsafe { libuv_sys::uv_os_homedir(buf, size) }).map(bytes_to_path)
+}
+
+/// Returns the directory for temporary files.
+pub fn tmpdir() -> Result<PathBuf> {
+    allocator::freeze();
+    fetch_bytes(|buf, size| unsafe { libuv_sys::uv_os_tmpdir(buf, size) }).map(bytes_to_path)
+}
+
+/// The current user's entry in the password database.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct Passwd {
+    /// The user's login name.
+    pub username: String,
+    /// The user ID.  `None` on Windows.
+    pub uid: Option<u32>,
+    /// The user's primary group ID.  `None` on Windows.
+    pub gid: Option<u32>,
+    /// The user's login shell.  `None` on Windows.
+    pub shell: Option<PathBuf>,
+    /// The user's home directory.
+    pub homedir: PathBuf,
+}
+
+unsafe fn c_path(s: *const c_char) -> Option<PathBuf> {
+    if s.is_null() {
+        None
+    } else {
+        Some(bytes_to_path(CStr::from_ptr(s).to_bytes().to_vec()))
+    }
+}
+
+// Windows reports -1 for the IDs.
+fn id(id: c_long) -> Option<u32> {
+    if id < 0 {
+        None
+    } else {
+        Some(id as u32)
+    }
+}
+
+/// Returns the current user's entry in the password database.  Unlike `homedir`, this ignores
+/// environment variables.
+pub fn get_passwd() -> Result<Passwd> {
+    allocator::freeze();
+    unsafe {
+        let mut raw: uv_passwd_t = mem::zeroed();
+        check(libuv_sys::uv_os_get_passwd(&mut raw))?;
+        let passwd = if raw.username.is_null() {
+            Err(Error::EINVAL)
+        } else {
+            Ok(Passwd {
+                username: CStr::from_ptr(raw.username).to_string_lossy().into_owned(),
+                uid: id(raw.uid),
+                gid: id(raw.gid),
+                shell: c_path(raw.shell),
+                homedir: c_path(raw.homedir).unwrap_or_default(),
+            })
+        };
+        libuv_sys::uv_os_free_passwd(&mut raw);
+        passwd
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn matches_std() {
+        assert_eq!(cwd().unwrap(), env::current_dir().unwrap());
+        assert_eq!(tmpdir().unwrap(), env::temp_dir());
+        assert_eq!(exepath().unwrap().file_name(), env::current_exe().unwrap().file_name());
+    }
+
+    #[test]
+    fn chdir_to_cwd() {
+        let dir = cwd().unwrap();
+        chdir(&dir).unwrap();
+        assert_eq!(chdir(Path::new("/nonexistent/libuv-rs-test")).unwrap_err().name(), "ENOENT");
+    }
+
+    #[test]
+    fn passwd_for_current_user() {
+        let passwd = get_passwd().unwrap();
+        assert!(!passwd.username.is_empty());
+        assert!(!passwd.homedir.as_os_str().is_empty());
+        #[cfg(unix)]
+        assert_eq!(passwd.uid, Some(unsafe { ::libc::getuid() }));
+        assert!(!homedir().unwrap().as_os_str().is_empty());
+    }
+}
@@ -0,0 +1,150 @@
+//! Error codes returned by libuv.
+
+use libc::{self, c_int};
+use libuv_sys;
+use std::error;
+use std::ffi::CStr;
+use std::fmt;
+use std::io;
+use std::result;
+
+/// An error code reported by a libuv function.  libuv uses negated `errno` values on Unix and its
+/// own translation of Windows error codes elsewhere; the name and message are always obtained
+/// from libuv itself so they are consistent across platforms.
+#[derive(Clone,Copy,PartialEq,Eq,Hash)]
+pub struct Error(c_int);
+
+/// The result type used throughout this crate.
+pub type Result<T> = result::Result<T, Error>;
+
+impl Error {
+    /// End of file; reported by reads when the peer has shut down its side of a stream.
+    pub const EOF: Error = Error(-4095);
+    /// Resource temporarily unavailable; reported by `try_write` when nothing can be written
+    /// without blocking.
+    #[cfg(unix)]
+    pub const EAGAIN: Error = Error(-libc::EAGAIN);
+    /// Resource temporarily unavailable; reported by `try_write` when nothing can be written
+    /// without blocking.
+    #[cfg(windows)]
+    pub const EAGAIN: Error = Error(-4088);
+    /// Bad file descriptor; also reported for handles and loops closed by `Loop::close_all`.
+    #[cfg(unix)]
+    pub const EBADF: Error = Error(-libc::EBADF);
+    /// Bad file descriptor; also reported for handles and loops closed by `Loop::close_all`.
+    #[cfg(windows)]
+    pub const EBADF: Error = Error(-4083);
+    /// Resource busy or locked.
+    #[cfg(unix)]
+    pub const EBUSY: Error = Error(-libc::EBUSY);
+    /// Resource busy or locked.
+    #[cfg(windows)]
+    pub const EBUSY: Error = Error(-4082);
+    /// Operation canceled.
+    #[cfg(unix)]
+    pub const ECANCELED: Error = Error(-libc::ECANCELED);
+    /// Operation canceled.
+    #[cfg(windows)]
+    pub const ECANCELED: Error = Error(-4081);
+    /// Invalid argument.
+    #[cfg(unix)]
+    pub const EINVAL: Error = Error(-libc::EINVAL);
+    /// Invalid argument.
+    #[cfg(windows)]
+    pub const EINVAL: Error = Error(-4071);
+    /// No buffer space available.
+    #[cfg(unix)]
+    pub const ENOBUFS: Error = Error(-libc::ENOBUFS);
+    /// No buffer space available.
+    #[cfg(windows)]
+    pub const ENOBUFS: Error = Error(-4060);
+    /// Connection timed out.
+    #[cfg(unix)]
+    pub const ETIMEDOUT: Error = Error(-libc::ETIMEDOUT);
+    /// Connection timed out.
+    #[cfg(windows)]
+    pub const ETIMEDOUT: Error = Error(-4039);
+    /// Function not implemented.
+    #[cfg(unix)]
+    pub const ENOSYS: Error = Error(-libc::ENOSYS);
+    /// Function not implemented.
+    #[cfg(windows)]
+    pub const ENOSYS: Error = Error(-4054);
+
+    /// Wraps a (negative) libuv error code.
+    pub fn from_raw(code: c_int) -> Error {
+        Error(code)
+    }
+
+    /// Returns the raw libuv error code.
+    pub fn code(&self) -> c_int {
+        self.0
+    }
+
+    /// Returns the symbolic name of the error, such as `"ECONNRESET"`.
+    pub fn name(&self) -> &'static str {
+        unsafe { CStr::from_ptr(libuv_sys::uv_err_name(self.0)).to_str().unwrap_or("UNKNOWN") }
+    }
+
+    /// Returns the human-readable description of the error.
+    pub fn message(&self) -> &'static str {
+        unsafe {
+            CStr::from_ptr(libuv_sys::uv_strerror(self.0)).to_str().unwrap_or("unknown error")
+        }
+    }
+}
+
+impl fmt::Debug for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Error({}, {})", self.name(), self.0)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.name(), self.message())
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<Error> for io::Error {
+    #[cfg(unix)]
+    fn from(err: Error) -> io::Error {
+        io::Error::from_raw_os_error(-err.0)
+    }
+
+    #[cfg(windows)]
+    fn from(err: Error) -> io::Error {
+        io::Error::new(io::ErrorKind::Other, err)
+    }
+}
+
+/// Converts a libuv status return into a `Result`, passing non-negative values through.
+pub fn check(rc: c_int) -> Result<c_int> {
+    if rc < 0 {
+        Err(Error(rc))
+    } else {
+        Ok(rc)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_passes_non_negative() {
+        assert_eq!(check(0), Ok(0));
+        assert_eq!(check(17), Ok(17));
+        assert_eq!(check(-22), Err(Error::from_raw(-22)));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn names_match_errno() {
+        let err = Error::from_raw(-::libc::EINVAL);
+        assert_eq!(err.name(), "EINVAL");
+        assert_eq!(io::Error::from(err).raw_os_error(), Some(::libc::EINVAL));
+    }
+}
@@ -0,0 +1,610 @@
+//! The event loop.
+
+use allocator;
+use error::{check, Error, Result};
+use handle;
+use libc::{c_int, c_void};
+use libuv_sys::{self, uv_handle_t, uv_handle_type, uv_loop_t, uv_os_fd_t, uv_run_mode};
+use std::cell::{Cell, RefCell};
+use std::fmt::Write;
+use std::future::Future;
+use signal::SIGPROF;
+use std::rc::Rc;
+use std::sync::OnceLock;
+use std::time::Duration;
+use task::Executor;
+use time::LoopTime;
+use thread_bound::ThreadBound;
+
+/// How long a call to `Loop::run` should wait for events.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum RunMode {
+    /// Runs until there are no more active and referenced handles or requests, or until
+    /// `Loop::stop` is called.
+    Default,
+    /// Polls for I/O once, blocking if there are no pending callbacks.
+    Once,
+    /// Polls for I/O once without blocking.
+    NoWait,
+}
+
+impl RunMode {
+    fn to_raw(self) -> uv_run_mode {
+        match self {
+            RunMode::Default => libuv_sys::UV_RUN_DEFAULT,
+            RunMode::Once => libuv_sys::UV_RUN_ONCE,
+            RunMode::NoWait => libuv_sys::UV_RUN_NOWAIT,
+        }
+    }
+}
+
+/// A description of one handle on a loop, as reported by `Loop::walk`.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct HandleInfo {
+    /// The handle itself.
+    pub raw: *mut uv_handle_t,
+    /// The kind of handle.
+    pub handle_type: uv_handle_type,
+    /// Whether the handle is doing something, such as a started timer or a reading stream.
+    pub active: bool,
+    /// Whether the handle keeps the loop alive while it is active.
+    pub referenced: bool,
+    /// Whether the handle is closing or closed.
+    pub closing: bool,
+    /// The file descriptor or `HANDLE`, for handles which have one.
+    pub fileno: Option<uv_os_fd_t>,
+}
+
+impl HandleInfo {
+    unsafe fn from_raw(raw: *mut uv_handle_t) -> HandleInfo {
+        let mut fd: uv_os_fd_t = ::std::mem::zeroed();
+        let fileno = if libuv_sys::uv_fileno(raw, &mut fd) == 0 {
+            Some(fd)
+        } else {
+            None
+        };
+        HandleInfo {
+            raw,
+            handle_type: (*raw).type_,
+            active: libuv_sys::uv_is_active(raw) != 0,
+            referenced: libuv_sys::uv_has_ref(raw) != 0,
+            closing: libuv_sys::uv_is_closing(raw) != 0,
+            fileno,
+        }
+    }
+
+    /// Returns a short name for the handle's type, such as `"tcp"`.
+    pub fn type_name(&self) -> &'static str {
+        match self.handle_type {
+            libuv_sys::UV_ASYNC => "async",
+            libuv_sys::UV_CHECK => "check",
+            libuv_sys::UV_FS_EVENT => "fs_event",
+            libuv_sys::UV_FS_POLL => "fs_poll",
+            libuv_sys::UV_HANDLE => "handle",
+            libuv_sys::UV_IDLE => "idle",
+            libuv_sys::UV_NAMED_PIPE => "pipe",
+            libuv_sys::UV_POLL => "poll",
+            libuv_sys::UV_PREPARE => "prepare",
+            libuv_sys::UV_PROCESS => "process",
+            libuv_sys::UV_STREAM => "stream",
+            libuv_sys::UV_TCP => "tcp",
+            libuv_sys::UV_TIMER => "timer",
+            libuv_sys::UV_TTY => "tty",
+            libuv_sys::UV_UDP => "udp",
+            libuv_sys::UV_SIGNAL => "signal",
+            libuv_sys::UV_FILE => "file",
+            _ => "unknown",
+        }
+    }
+}
+
+struct LoopInner {
+    raw: *mut uv_loop_t,
+    running: Cell<bool>,
+    // Set by `close_all` to the loop's time when it closed the loop.
+    closed_at: Cell<Option<LoopTime>>,
+    // The default loop's storage belongs to libuv.
+    is_default: bool,
+    executor: RefCell<Option<Executor>>,
+}
+
+/// Configures a `Loop` before it is created, in place of the variadic `uv_loop_configure`.
+#[derive(Clone,Debug,Default)]
+pub struct LoopBuilder {
+    block_signals: Vec<i32>,
+}
+
+impl LoopBuilder {
+    /// Returns a builder for a loop with the default configuration.
+    pub fn new() -> LoopBuilder {
+        LoopBuilder::default()
+    }
+
+    /// Blocks `signum` while the loop polls for events, so that it does not cut the wait short
+    /// with `EINTR`.  libuv only supports `signal::SIGPROF`, which profilers such as `perf`
+    /// deliver at a high rate; other signals make `build` fail with `EINVAL`, and any signal
+    /// makes it fail with `ENOSYS` on Windows.
+    pub fn block_signal(&mut self, signum: i32) -> &mut LoopBuilder {
+        self.block_signals.push(signum);
+        self
+    }
+
+    /// Creates the loop.
+    pub fn build(&self) -> Result<Loop> {
+        if self.block_signals.iter().any(|&signum| signum != SIGPROF) {
+            return Err(Error::EINVAL);
+        }
+        Loop::init(|raw| {
+            for &signum in &self.block_signals {
+                unsafe {
+                    check(libuv_sys::uv_loop_configure(raw,
+                                                       libuv_sys::UV_LOOP_BLOCK_SIGNAL,
+                                                       signum as c_int))?;
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+/// A libuv event loop.  `Loop` is a reference-counted handle, so clones can be captured by
+/// callbacks; the underlying loop is closed when the last clone is dropped.  Loops are not `Send`
+/// and all handles created on a loop must be used from the loop's thread.
+#[derive(Clone)]
+pub struct Loop {
+    inner: Rc<LoopInner>,
+}
+
+impl Loop {
+    /// Creates and initializes a new event loop with the default configuration.
+    pub fn new() -> Result<Loop> {
+        LoopBuilder::new().build()
+    }
+
+    // Initializes a loop and applies `configure` to it before anything else can touch it.
+    fn init<F>(configure: F) -> Result<Loop>
+        where F: FnOnce(*mut uv_loop_t) -> Result<()>
+    {
+        allocator::freeze();
+        unsafe {
+            let raw = ::libc::calloc(1, libuv_sys::uv_loop_size()) as *mut uv_loop_t;
+            if raw.is_null() {
+                panic!("out of memory allocating a loop");
+            }
+            if let Err(err) = check(libuv_sys::uv_loop_init(raw)) {
+                ::libc::free(raw as *mut c_void);
+                return Err(err);
+            }
+            if let Err(err) = configure(raw) {
+                libuv_sys::uv_loop_close(raw);
+                ::libc::free(raw as *mut c_void);
+                return Err(err);
+            }
+            Ok(Loop::wrap(raw, false))
+        }
+    }
+
+    unsafe fn wrap(raw: *mut uv_loop_t, is_default: bool) -> Loop {
+        let inner = Rc::new(LoopInner {
+            raw,
+            running: Cell::new(false),
+            closed_at: Cell::new(None),
+            is_default,
+            executor: RefCell::new(None),
+        });
+        (*raw).data = Rc::as_ptr(&inner) as *mut c_void;
+        Loop { inner }
+    }
+
+    /// Returns libuv's process-wide default loop, which C libraries built on libuv often use.
+    /// The loop belongs to the first thread which calls this, and is closed, once, when that
+    /// thread exits and the last clone has been dropped.
+    ///
+    /// This is not the `Default` trait, since the result is shared rather than fresh.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called from any other thread, or if libuv cannot initialize the loop.
+    #[allow(clippy::should_implement_trait)]
+    pub fn default() -> Loop {
+        static DEFAULT: OnceLock<ThreadBound<Loop>> = OnceLock::new();
+
+        let bound = DEFAULT.get_or_init(|| {
+            allocator::freeze();
+            let raw = unsafe { libuv_sys::uv_default_loop() };
+            if raw.is_null() {
+                panic!("libuv failed to initialize the default loop");
+            }
+            ThreadBound::new(unsafe { Loop::wrap(raw, true) })
+        });
+        if !bound.accessible() {
+            panic!("the default loop belongs to another thread");
+        }
+        bound.with(|lp| lp.clone())
+    }
+
+    /// Returns true if this is the default loop.
+    pub fn is_default(&self) -> bool {
+        self.inner.is_default
+    }
+
+    /// Returns the underlying `uv_loop_t`.  The pointer remains valid as long as any clone of
+    /// this `Loop` is alive.
+    pub fn as_raw(&self) -> *mut uv_loop_t {
+        self.inner.raw
+    }
+
+    /// Returns the underlying `uv_loop_t` for creating a handle or request on it, or `EBADF`
+    /// if `close_all` has closed the loop.
+    pub(crate) fn checked_raw(&self) -> Result<*mut uv_loop_t> {
+        if self.is_closed() {
+            return Err(Error::EBADF);
+        }
+        Ok(self.inner.raw)
+    }
+
+    /// Returns true if `close_all` has closed the loop.
+    pub fn is_closed(&self) -> bool {
+        self.inner.closed_at.get().is_some()
+    }
+
+    /// Returns a new reference to the `Loop` which owns `raw`, which must have been created by
+    /// this crate and still be alive.  Callbacks use this to recover the loop from a handle.
+    pub(crate) unsafe fn from_raw(raw: *mut uv_loop_t) -> Loop {
+        let inner = (*raw).data as *const LoopInner;
+        Rc::increment_strong_count(inner);
+        Loop { inner: Rc::from_raw(inner) }
+    }
+
+    /// Runs the event loop.  Returns true if there are still active handles or requests, which
+    /// can only happen in `Once` and `NoWait` modes or after `stop`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called from a callback while the loop is already running; libuv does not
+    /// support reentrant runs.
+    pub fn run(&self, mode: RunMode) -> bool {
+        if self.inner.running.get() {
+            panic!("Loop::run called reentrantly");
+        }
+        if self.is_closed() {
+            return false;
+        }
+        self.inner.running.set(true);
+        let rc = unsafe { libuv_sys::uv_run(self.inner.raw, mode.to_raw()) };
+        self.inner.running.set(false);
+        rc != 0
+    }
+
+    /// Stops the loop at the end of the current iteration, causing `run` to return as soon as
+    /// possible.
+    pub fn stop(&self) {
+        if !self.is_closed() {
+            unsafe { libuv_sys::uv_stop(self.inner.raw) }
+        }
+    }
+
+    /// Returns the file descriptor of the loop's poller (epoll, kqueue or event ports), which
+    /// becomes readable when the loop has I/O to process.  `None` on Windows, which has none,
+    /// and once the loop is closed.
+    pub fn backend_fd(&self) -> Option<c_int> {
+        if self.is_closed() {
+            return None;
+        }
+        let fd = unsafe { libuv_sys::uv_backend_fd(self.inner.raw) };
+        if fd < 0 {
+            None
+        } else {
+            Some(fd)
+        }
+    }
+
+    /// Returns how long the loop would block waiting for I/O: zero if callbacks are already
+    /// due, I/O was started since the last turn and has yet to reach the poller, or the loop is
+    /// closed, and `None` for no limit.
+    pub fn backend_timeout(&self) -> Option<Duration> {
+        if self.is_closed() || unsafe { watchers_pending(self.inner.raw) } {
+            return Some(Duration::from_secs(0));
+        }
+        let timeout = unsafe { libuv_sys::uv_backend_timeout(self.inner.raw) };
+        if timeout < 0 {
+            None
+        } else {
+            Some(Duration::from_millis(timeout as u64))
+        }
+    }
+
+    /// Runs one non-blocking turn of a loop which is embedded in another event loop.  Call it
+    /// whenever `backend_fd` becomes readable or `backend_timeout` has elapsed, and wait no
+    /// longer than the new `backend_timeout` before calling it again.  Returns `alive`.
+    pub fn poll_embedded(&self) -> bool {
+        self.run(RunMode::NoWait)
+    }
+
+    /// Returns the loop's cached time, which is updated at the start of each iteration and is
+    /// what timers are measured against.  A closed loop's clock stops when it closes.
+    pub fn now(&self) -> LoopTime {
+        match self.inner.closed_at.get() {
+            Some(time) => time,
+            None => LoopTime::from_millis(unsafe { libuv_sys::uv_now(self.inner.raw) }),
+        }
+    }
+
+    /// Refreshes the cached time, for callbacks which ran long enough to make it stale.
+    pub fn update_time(&self) {
+        if !self.is_closed() {
+            unsafe { libuv_sys::uv_update_time(self.inner.raw) }
+        }
+    }
+
+    /// Returns true if there are active and referenced handles or requests on the loop.
+    pub fn alive(&self) -> bool {
+        !self.is_closed() && unsafe { libuv_sys::uv_loop_alive(self.inner.raw) != 0 }
+    }
+
+    /// Calls `f` with a description of each handle on the loop, including ones which are
+    /// closing; libuv's own internal handles are left out.  The handles are listed before `f`
+    /// is first called, so `f` may close them.
+    pub fn walk<F>(&self, mut f: F)
+        where F: FnMut(&HandleInfo)
+    {
+        extern "C" fn walk_cb(raw: *mut uv_handle_t, arg: *mut c_void) {
+            unsafe {
+                let handles = &mut *(arg as *mut Vec<HandleInfo>);
+                handles.push(HandleInfo::from_raw(raw));
+            }
+        }
+
+        if self.is_closed() {
+            return;
+        }
+        let mut handles: Vec<HandleInfo> = Vec::new();
+        unsafe {
+            let arg = &mut handles as *mut Vec<HandleInfo> as *mut c_void;
+            libuv_sys::uv_walk(self.inner.raw, walk_cb, arg);
+        }
+        for handle in &handles {
+            f(handle);
+        }
+    }
+
+    /// Describes every handle on the loop, one per line, in the style of libuv's
+    /// `uv_print_all_handles`: the flags are `R` for referenced, `A` for active and `C` for
+    /// closing.  Handy for finding what keeps a loop from exiting.
+    pub fn debug_dump(&self) -> String {
+        let mut out = String::new();
+        self.walk(|handle| {
+            let flag = |set, c| if set { c } else { '-' };
+            let _ = write!(out,
+                           "[{}{}{}] {:<8} {:p}",
+                           flag(handle.referenced, 'R'),
+                           flag(handle.active, 'A'),
+                           flag(handle.closing, 'C'),
+                           handle.type_name(),
+                           handle.raw);
+            if let Some(fd) = handle.fileno {
+                let _ = write!(out, " fd={:?}", fd);
+            }
+            out.push('\n');
+        });
+        out
+    }
+
+    /// Closes every handle on the loop which is not already closing, runs the loop until the
+    /// close callbacks and any pending requests have completed, and closes the loop with
+    /// `uv_loop_close`.  Returns the types of the handles which had to be closed.  Fails with
+    /// `EBUSY` if the loop is running and `EBADF` if it is already closed, in either case
+    /// without touching anything.
+    ///
+    /// The `Loop` is unusable afterwards: `run` returns at once and creating handles on it fails
+    /// with `EBADF`.  Wrappers for the closed handles fail the same way, and dropping them frees
+    /// what they own.  Closing the default loop lets libuv create a fresh one for C code which
+    /// asks for it, but `Loop::default` keeps returning the closed one.
+    pub fn close_all(&self) -> Result<Vec<uv_handle_type>> {
+        if self.inner.running.get() {
+            return Err(Error::EBUSY);
+        }
+        if self.is_closed() {
+            return Err(Error::EBADF);
+        }
+        // Like the handles the loop's users own, the executor's are closed properly.
+        if let Some(executor) = self.inner.executor.borrow_mut().take() {
+            executor.shutdown();
+        }
+        let raw = self.inner.raw;
+        let mut closed = Vec::new();
+        unsafe {
+            // Debug builds of libuv overwrite the whole loop when closing it.
+            let data = (*raw).data;
+            let now = loop {
+                self.walk(|handle| {
+                    if !handle.closing {
+                        closed.push(handle.handle_type);
+                        libuv_sys::uv_close(handle.raw, handle::orphan_close_cb);
+                    }
+                });
+                self.inner.running.set(true);
+                libuv_sys::uv_run(raw, libuv_sys::UV_RUN_DEFAULT);
+                self.inner.running.set(false);
+                let now = libuv_sys::uv_now(raw);
+                // Otherwise a callback opened another handle or stopped the loop early.
+                if libuv_sys::uv_loop_close(raw) == 0 {
+                    break now;
+                }
+            };
+            (*raw).data = data;
+            self.inner.closed_at.set(Some(LoopTime::from_millis(now)));
+        }
+        Ok(closed)
+    }
+
+    /// Queues a future to be polled on this loop's thread.  The future does not need to be
+    /// `Send`, but the `Waker`s handed to it are, so it can be woken by events on other threads.
+    /// The loop stays alive while spawned futures are pending.
+    pub fn spawn_local<F>(&self, future: F) -> Result<()>
+        where F: Future<Output = ()> + 'static
+    {
+        self.executor()?.spawn(Box::pin(future));
+        Ok(())
+    }
+
+    fn executor(&self) -> Result<Executor> {
+        self.checked_raw()?;
+        let mut slot = self.inner.executor.borrow_mut();
+        if let Some(ref executor) = *slot {
+            return Ok(executor.clone());
+        }
+        let executor = Executor::new(self)?;
+        *slot = Some(executor.clone());
+        Ok(executor)
+    }
+}
+
+// The start of `uv_loop_t` on Unix, as every libuv 1.x release lays it out, up to the queue of
+// I/O watchers started or changed since the poller was last updated.  libuv only hands that
+// queue to epoll or kqueue at the start of its next poll, and `uv_backend_timeout` ignores it.
+#[cfg(unix)]
+#[repr(C)]
+struct RawLoopHead {
+    data: *mut c_void,
+    active_handles: ::libc::c_uint,
+    handle_queue: [*mut c_void; 2],
+    active_reqs: [*mut c_void; 2],
+    stop_flag: ::libc::c_uint,
+    flags: ::libc::c_ulong,
+    backend_fd: c_int,
+    pending_queue: [*mut c_void; 2],
+    watcher_queue: [*mut c_void; 2],
+}
+
+// Whether some I/O watcher is not yet known to the poller, so that blocking on the backend fd
+// could wait for events which will never arrive.
+#[cfg(unix)]
+unsafe fn watchers_pending(raw: *mut uv_loop_t) -> bool {
+    let queue = &(*(raw as *const RawLoopHead)).watcher_queue;
+    // An empty queue points at itself.
+    queue[0] != queue as *const _ as *mut c_void
+}
+
+#[cfg(windows)]
+unsafe fn watchers_pending(_raw: *mut uv_loop_t) -> bool {
+    false
+}
+
+impl LoopInner {
+    unsafe fn free(&self) {
+        if !self.is_default {
+            ::libc::free(self.raw as *mut c_void);
+        }
+    }
+}
+
+impl Drop for LoopInner {
+    fn drop(&mut self) {
+        // Internal helpers own handles on this loop, so they must be closed first.
+        if let Some(executor) = self.executor.borrow_mut().take() {
+            executor.shutdown();
+        }
+
+        unsafe {
+            if self.closed_at.get().is_some() {
+                self.free();
+                return;
+            }
+            // Handles which were dropped but never saw their close callback keep the loop busy;
+            // give them one non-blocking turn to finish.
+            if libuv_sys::uv_loop_close(self.raw) == 0 {
+                self.free();
+                return;
+            }
+            libuv_sys::uv_run(self.raw, libuv_sys::UV_RUN_NOWAIT);
+            if libuv_sys::uv_loop_close(self.raw) == 0 {
+                self.free();
+            }
+            // Otherwise some handle is still open and refers to this loop, so the storage is
+            // deliberately leaked.
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use timer::Timer;
+
+    #[test]
+    fn empty_loop_exits() {
+        let lp = Loop::new().unwrap();
+        assert!(!lp.alive());
+        assert!(!lp.run(RunMode::Default));
+    }
+
+    #[test]
+    fn walk_lists_handles() {
+        let lp = Loop::new().unwrap();
+        let timer = Timer::new(&lp).unwrap();
+        timer.start(Duration::from_secs(60), Duration::from_secs(0), || {}).unwrap();
+        let mut handles = Vec::new();
+        lp.walk(|handle| handles.push(*handle));
+        let raw = timer.as_raw() as *mut uv_handle_t;
+        let info = handles.iter().find(|handle| handle.raw == raw).unwrap();
+        assert_eq!(info.type_name(), "timer");
+        assert!(info.active && info.referenced && !info.closing);
+        assert_eq!(info.fileno, None);
+        assert!(lp.debug_dump().contains("[RA-] timer"));
+    }
+
+    #[test]
+    fn close_all_closes_leaked_handles() {
+        let lp = Loop::new().unwrap();
+        let leaked = Timer::new(&lp).unwrap();
+        leaked.start(Duration::from_secs(60), Duration::from_secs(0), || {}).unwrap();
+        ::std::mem::forget(leaked);
+        // Already closing, so not reported.
+        drop(Timer::new(&lp).unwrap());
+        assert_eq!(lp.close_all(), Ok(vec![libuv_sys::UV_TIMER]));
+        assert!(lp.is_closed());
+        assert!(!lp.alive());
+        assert!(!lp.run(RunMode::Default));
+        assert_eq!(Timer::new(&lp).err(), Some(Error::EBADF));
+        assert_eq!(lp.close_all(), Err(Error::EBADF));
+    }
+
+    #[test]
+    fn close_all_disables_live_wrappers() {
+        let lp = Loop::new().unwrap();
+        let timer = Timer::new(&lp).unwrap();
+        timer.start(Duration::from_secs(60), Duration::from_secs(0), || {}).unwrap();
+        assert_eq!(lp.close_all(), Ok(vec![libuv_sys::UV_TIMER]));
+        assert_eq!(timer.start(Duration::from_millis(1), Duration::from_secs(0), || {}),
+                   Err(Error::EBADF));
+        // Frees the handle and its callback.
+        drop(timer);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn builder_blocks_sigprof() {
+        let lp = LoopBuilder::new().block_signal(SIGPROF).build().unwrap();
+        assert!(!lp.run(RunMode::Default));
+        assert_eq!(LoopBuilder::new().block_signal(::libc::SIGHUP).build().err(),
+                   Some(Error::EINVAL));
+    }
+
+    #[test]
+    fn default_loop_is_thread_bound() {
+        let lp = Loop::default();
+        assert!(lp.is_default());
+        assert_eq!(lp.as_raw(), unsafe { libuv_sys::uv_default_loop() });
+        assert_eq!(Loop::default().as_raw(), lp.as_raw());
+        assert!(!Loop::new().unwrap().is_default());
+        assert!(::std::thread::spawn(|| drop(Loop::default())).join().is_err());
+    }
+
+    #[test]
+    fn clones_share_loop() {
+        let lp = Loop::new().unwrap();
+        let lp2 = lp.clone();
+        assert_eq!(lp.as_raw(), lp2.as_raw());
+    }
+}
@@ -0,0 +1,126 @@
+// Storage management shared by the handle wrappers.  The structs in libuv-sys only reproduce the
+// public prefix of most handle types, so storage is always sized by asking libuv, and it must
+// stay allocated until the close callback has run.
+
+use error::{Error, Result};
+use libc::{self, c_void};
+use libuv_sys::{uv_handle_t, uv_handle_type, uv_handle_size, uv_close, uv_is_closing, uv_req_type,
+                uv_req_size};
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
+use std::ptr;
+
+thread_local! {
+    // Handles closed by `Loop::close_all` whose wrappers have not been dropped yet.  Their
+    // storage and `data` stay allocated until the wrapper's `close` finds them here.
+    static ORPHANS: RefCell<HashSet<usize>> = RefCell::new(HashSet::new());
+}
+
+/// Allocates zeroed storage for a handle of type `ty`.  Storage which was never successfully
+/// initialized must be released with `free`; anything else must go through `close`.
+pub unsafe fn alloc<H>(ty: uv_handle_type) -> *mut H {
+    let size = uv_handle_size(ty);
+    let ptr = libc::calloc(1, size);
+    if ptr.is_null() {
+        panic!("out of memory allocating a {:?} handle", ty);
+    }
+    ptr as *mut H
+}
+
+/// Releases handle or request storage.
+pub unsafe fn free<H>(handle: *mut H) {
+    libc::free(handle as *mut c_void);
+}
+
+// Frees a closed handle's storage and its `data`, which is either null or a `Box<D>`.
+unsafe fn release<D>(handle: *mut uv_handle_t) {
+    let data = (*handle).data as *mut D;
+    (*handle).data = ptr::null_mut();
+    if !data.is_null() {
+        drop(Box::from_raw(data));
+    }
+    free(handle);
+}
+
+/// Closes a handle whose `data` field is either null or a `Box<D>`, freeing both once libuv
+/// reports that the close is complete.  Does nothing if the handle is already closing, unless
+/// `Loop::close_all` closed it, in which case both are freed at once.
+pub unsafe fn close<H, D>(handle: *mut H) {
+    extern "C" fn close_cb<D>(handle: *mut uv_handle_t) {
+        unsafe { release::<D>(handle) }
+    }
+
+    let handle = handle as *mut uv_handle_t;
+    if uv_is_closing(handle) == 0 {
+        uv_close(handle, close_cb::<D>);
+    } else if ORPHANS.with(|orphans| orphans.borrow_mut().remove(&(handle as usize))) {
+        release::<D>(handle);
+    }
+}
+
+/// The close callback `Loop::close_all` uses for handles it does not own.  The handle's storage
+/// is left for its wrapper to free with `close`.
+pub extern "C" fn orphan_close_cb(handle: *mut uv_handle_t) {
+    ORPHANS.with(|orphans| orphans.borrow_mut().insert(handle as usize));
+}
+
+/// Fails with `EBADF` if the handle is closing or closed, which the wrappers only see after
+/// `Loop::close_all` or a stream timeout closed it underneath them.  libuv asserts or misbehaves
+/// when most operations are attempted on such a handle.
+pub unsafe fn ensure_open<H>(handle: *mut H) -> Result<()> {
+    if uv_is_closing(handle as *const uv_handle_t) != 0 {
+        return Err(Error::EBADF);
+    }
+    Ok(())
+}
+
+/// Allocates storage for a request of type `ty`, to be released with `free` once libuv has
+/// invoked the request's callback.
+pub unsafe fn alloc_req<R>(ty: uv_req_type) -> *mut R {
+    let size = uv_req_size(ty);
+    let ptr = libc::calloc(1, size);
+    if ptr.is_null() {
+        panic!("out of memory allocating a {:?} request", ty);
+    }
+    ptr as *mut R
+}
+
+/// Holds a callback which libuv may invoke repeatedly.  The callback is moved out while it runs,
+/// so it may replace or clear itself (for instance by calling `read_stop`) without aliasing.
+pub struct CallbackCell<T> {
+    value: RefCell<Option<T>>,
+    generation: Cell<u64>,
+}
+
+impl<T> CallbackCell<T> {
+    pub fn new() -> Self {
+        CallbackCell {
+            value: RefCell::new(None),
+            generation: Cell::new(0),
+        }
+    }
+
+    pub fn set(&self, value: T) {
+        self.generation.set(self.generation.get() + 1);
+        *self.value.borrow_mut() = Some(value);
+    }
+
+    pub fn clear(&self) {
+        self.generation.set(self.generation.get() + 1);
+        *self.value.borrow_mut() = None;
+    }
+
+    /// Runs `f` on the stored callback, if any, and puts it back unless it was replaced or
+    /// cleared in the meantime.
+    pub fn with<F, R>(&self, f: F) -> Option<R>
+        where F: FnOnce(&mut T) -> R
+    {
+        let generation = self.generation.get();
+        let mut value = self.value.borrow_mut().take();
+        let result = value.as_mut().map(f);
+        if self.generation.get() == generation {
+            *self.value.borrow_mut() = value;
+        }
+        result
+    }
+}
@@ -0,0 +1,267 @@
+//! Copying everything one stream reads to another.
+//!
+//! `pipe` forwards data from a source stream to a destination, stops reading while the
+//! destination's write queue is above a high-water mark, and shuts down the destination's
+//! writing side once the source reaches the end of its data.  Two pipes running in opposite
+//! directions between the same streams make a proxy, with each direction half-closing
+//! independently.
+
+use error::{Error, Result};
+use std::cell::{Cell, RefCell};
+use std::rc::{Rc, Weak};
+use stream::Stream;
+
+// Reading stops once more than this is queued on the destination, and resumes once the queue
+// has fallen below `LOW_WATER`.
+const HIGH_WATER: usize = 64 * 1024;
+const LOW_WATER: usize = 16 * 1024;
+
+/// The number of bytes a pipe moved.
+#[derive(Clone,Copy,Debug,Default,PartialEq,Eq)]
+pub struct PipeStats {
+    /// Bytes read from the source.
+    pub read: u64,
+    /// Bytes written to the destination.  Less than `read` only if the pipe failed.
+    pub written: u64,
+}
+
+type DoneCallback = Box<dyn FnOnce(Result<()>, PipeStats)>;
+
+struct Splice<R, W> {
+    // Taken once the pipe finishes or is stopped.
+    streams: RefCell<Option<(Rc<R>, Rc<W>)>>,
+    stats: Cell<PipeStats>,
+    paused: Cell<bool>,
+    done: Cell<bool>,
+    cb: RefCell<Option<DoneCallback>>,
+}
+
+impl<R: Stream, W: Stream> Splice<R, W> {
+    fn streams(&self) -> Option<(Rc<R>, Rc<W>)> {
+        self.streams.borrow().clone()
+    }
+
+    // Stops reading and lets go of both streams, returning the callback if it has not run yet.
+    fn release(&self) -> Option<DoneCallback> {
+        if self.done.replace(true) {
+            return None;
+        }
+        let streams = self.streams.borrow_mut().take();
+        if let Some((src, _)) = streams {
+            // Drops the read callback, and with it the callback's reference to the pipe.
+            let _ = src.read_stop();
+        }
+        self.cb.borrow_mut().take()
+    }
+}
+
+// Lets a `PipeGuard` stop a pipe without naming its stream types.
+trait Stop {
+    fn stop(&self);
+}
+
+impl<R: Stream, W: Stream> Stop for Splice<R, W> {
+    fn stop(&self) {
+        drop(self.release());
+    }
+}
+
+/// A running pipe, which stops when dropped.
+#[must_use = "the pipe stops when the guard is dropped"]
+pub struct PipeGuard {
+    splice: Weak<dyn Stop>,
+}
+
+impl PipeGuard {
+    /// Stops the pipe, as dropping the guard does.
+    pub fn stop(self) {}
+}
+
+impl Drop for PipeGuard {
+    fn drop(&mut self) {
+        if let Some(splice) = self.splice.upgrade() {
+            splice.stop();
+        }
+    }
+}
+
+/// Forwards everything read from `src` to `dst`, then shuts down `dst` for writing.  `cb`
+/// receives the outcome and the byte counts once the shutdown completes, or on the first read,
+/// write or shutdown error, after which nothing more is read.  Neither stream is closed.
+///
+/// The pipe keeps both streams alive until it finishes or the returned guard is dropped.
+/// Dropping the guard stops reading from `src` and lets go of both streams without calling
+/// `cb`; data already read may still be written to `dst`.  Nothing else should read from `src`
+/// meanwhile; writes to `dst` from elsewhere are interleaved with the forwarded data.
+pub fn pipe<R, W, F>(src: Rc<R>, dst: Rc<W>, cb: F) -> Result<PipeGuard>
+    where R: Stream + 'static,
+          W: Stream + 'static,
+          F: FnOnce(Result<()>, PipeStats) + 'static
+{
+    let splice = Rc::new(Splice {
+        streams: RefCell::new(Some((src.clone(), dst))),
+        stats: Cell::new(PipeStats::default()),
+        paused: Cell::new(false),
+        done: Cell::new(false),
+        cb: RefCell::new(Some(Box::new(cb))),
+    });
+    read(&splice, &src)?;
+    let splice: Rc<dyn Stop> = splice;
+    Ok(PipeGuard { splice: Rc::downgrade(&splice) })
+}
+
+fn read<R, W>(splice: &Rc<Splice<R, W>>, src: &R) -> Result<()>
+    where R: Stream + 'static,
+          W: Stream + 'static
+{
+    let splice2 = splice.clone();
+    src.read_start(move |data| on_read(&splice2, data))
+}
+
+fn on_read<R, W>(splice: &Rc<Splice<R, W>>, data: Result<&[u8]>)
+    where R: Stream + 'static,
+          W: Stream + 'static
+{
+    let (src, dst) = match splice.streams() {
+        Some(streams) => streams,
+        None => return,
+    };
+    match data {
+        Ok(data) => {
+            if data.is_empty() {
+                return;
+            }
+            let n = data.len() as u64;
+            let mut stats = splice.stats.get();
+            stats.read += n;
+            splice.stats.set(stats);
+            let splice2 = splice.clone();
+            let rc = dst.write(data.to_vec(), move |result| on_written(&splice2, n, result));
+            if let Err(err) = rc {
+                finish(splice, Err(err));
+            } else if dst.write_queue_size() > HIGH_WATER {
+                splice.paused.set(true);
+                let _ = src.read_stop();
+            }
+        }
+        Err(Error::EOF) => {
+            let _ = src.read_stop();
+            // The shutdown waits for the writes already queued.
+            let splice2 = splice.clone();
+            if let Err(err) = dst.shutdown(move |result| finish(&splice2, result)) {
+                finish(splice, Err(err));
+            }
+        }
+        Err(err) => finish(splice, Err(err)),
+    }
+}
+
+fn on_written<R, W>(splice: &Rc<Splice<R, W>>, n: u64, result: Result<()>)
+    where R: Stream + 'static,
+          W: Stream + 'static
+{
+    let (src, dst) = match splice.streams() {
+        Some(streams) => streams,
+        None => return,
+    };
+    if let Err(err) = result {
+        finish(splice, Err(err));
+        return;
+    }
+    let mut stats = splice.stats.get();
+    stats.written += n;
+    splice.stats.set(stats);
+    if splice.paused.get() && dst.write_queue_size() < LOW_WATER {
+        splice.paused.set(false);
+        if let Err(err) = read(splice, &src) {
+            finish(splice, Err(err));
+        }
+    }
+}
+
+fn finish<R: Stream, W: Stream>(splice: &Splice<R, W>, result: Result<()>) {
+    if let Some(cb) = splice.release() {
+        cb(result, splice.stats.get());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use event_loop::{Loop, RunMode};
+    use std::io::{Read, Write};
+    use std::net::{Shutdown, TcpListener};
+    use std::thread;
+    use tcp::Tcp;
+
+    // Connects to a new listener, returning the accepted end and the connected stream.
+    fn connect(lp: &Loop) -> (thread::JoinHandle<::std::net::TcpStream>, Rc<Tcp>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let peer = thread::spawn(move || listener.accept().unwrap().0);
+        let conn = Rc::new(RefCell::new(None));
+        let conn2 = conn.clone();
+        Tcp::connect(lp, &addr, move |tcp| *conn2.borrow_mut() = Some(tcp.unwrap())).unwrap();
+        while conn.borrow().is_none() {
+            lp.run(RunMode::Once);
+        }
+        let tcp = conn.borrow_mut().take().unwrap();
+        (peer, Rc::new(tcp))
+    }
+
+    #[test]
+    fn forwards_until_eof() {
+        let lp = Loop::new().unwrap();
+        let (src_peer, src) = connect(&lp);
+        let (dst_peer, dst) = connect(&lp);
+        let data: Vec<u8> = (0..4 << 20).map(|i: u32| (i % 251) as u8).collect();
+
+        let data2 = data.clone();
+        let mut src_peer = src_peer.join().unwrap();
+        let writer = thread::spawn(move || {
+            src_peer.write_all(&data2).unwrap();
+            src_peer.shutdown(Shutdown::Write).unwrap();
+        });
+        let mut dst_peer = dst_peer.join().unwrap();
+        let reader = thread::spawn(move || {
+            let mut buf = Vec::new();
+            dst_peer.read_to_end(&mut buf).unwrap();
+            buf
+        });
+
+        let outcome = Rc::new(RefCell::new(None));
+        let outcome2 = outcome.clone();
+        let _guard = pipe(src, dst, move |result, stats| {
+                *outcome2.borrow_mut() = Some((result, stats))
+            })
+            .unwrap();
+        lp.run(RunMode::Default);
+
+        let len = data.len() as u64;
+        assert_eq!(*outcome.borrow(), Some((Ok(()), PipeStats { read: len, written: len })));
+        writer.join().unwrap();
+        assert!(reader.join().unwrap() == data);
+    }
+
+    #[test]
+    fn stop_releases_streams() {
+        let lp = Loop::new().unwrap();
+        let (src_peer, src) = connect(&lp);
+        let (dst_peer, dst) = connect(&lp);
+        let mut src_peer = src_peer.join().unwrap();
+        let _dst_peer = dst_peer.join().unwrap();
+
+        let called = Rc::new(Cell::new(false));
+        let called2 = called.clone();
+        let guard = pipe(src.clone(), dst.clone(), move |_, _| called2.set(true)).unwrap();
+        // Stopping leaves the write of whatever was read in progress.
+        src_peer.write_all(b"hello").unwrap();
+        lp.run(RunMode::Once);
+        guard.stop();
+        // Nothing is reading any more, so the loop only waits for that write.
+        lp.run(RunMode::Default);
+        assert!(!called.get());
+        assert_eq!(Rc::strong_count(&src), 1);
+        assert_eq!(Rc::strong_count(&dst), 1);
+    }
+}
@@ -0,0 +1,239 @@
+//! Passing handles between processes over IPC pipes.
+//!
+//! A pipe created with `ipc` set can carry TCP, pipe and UDP handles alongside its data, which is
+//! how a parent process hands listening or accepted sockets to its workers.  The receiving side
+//! accepts each handle into a new wrapper on its own loop.
+
+use error::{check, Error, Result};
+use event_loop::Loop;
+use libuv_sys::{self, uv_pipe_t, uv_stream_t};
+use pipe::Pipe;
+use sealed::Sealed;
+use std::rc::Rc;
+use stream::{self, Stream};
+use tcp::Tcp;
+use udp::Udp;
+
+/// Handles which can be sent over an IPC pipe.
+pub trait SendHandle: Sealed + 'static {
+    /// Returns the handle to send, as libuv expects it.
+    fn as_send_handle(&self) -> *mut uv_stream_t;
+}
+
+impl SendHandle for Tcp {
+    fn as_send_handle(&self) -> *mut uv_stream_t {
+        self.as_raw_stream()
+    }
+}
+
+impl SendHandle for Pipe {
+    fn as_send_handle(&self) -> *mut uv_stream_t {
+        self.as_raw_stream()
+    }
+}
+
+impl SendHandle for Udp {
+    fn as_send_handle(&self) -> *mut uv_stream_t {
+        self.as_raw() as *mut uv_stream_t
+    }
+}
+
+impl<T: SendHandle> Sealed for Rc<T> {}
+
+// Sending a shared handle keeps one reference until the write completes, so the same listening
+// socket can be handed to several workers.
+impl<T: SendHandle> SendHandle for Rc<T> {
+    fn as_send_handle(&self) -> *mut uv_stream_t {
+        (**self).as_send_handle()
+    }
+}
+
+/// A handle received over an IPC pipe.
+pub enum ReceivedHandle {
+    /// A TCP socket, either listening or connected.
+    Tcp(Tcp),
+    /// A pipe.
+    Pipe(Pipe),
+    /// A UDP socket.
+    Udp(Udp),
+}
+
+/// The result of a read from an IPC pipe.
+pub struct IpcRead<'a> {
+    /// The bytes read.
+    pub data: &'a [u8],
+    /// The handles which arrived with the data.  Each is accepted into a new wrapper; an entry is
+    /// an error if that failed.
+    pub handles: Vec<Result<ReceivedHandle>>,
+}
+
+// Only called from the read callback, while the pipe is necessarily still open.
+unsafe fn accept_pending(server: *mut uv_pipe_t) -> Vec<Result<ReceivedHandle>> {
+    let lp = Loop::from_raw((*server).loop_);
+    let mut handles = Vec::new();
+    loop {
+        let pending = libuv_sys::uv_pipe_pending_count(server);
+        if pending == 0 {
+            break;
+        }
+        handles.push(accept_one(&lp, server));
+        // Only possible if a descriptor could not even be discarded; it would never go away.
+        if libuv_sys::uv_pipe_pending_count(server) == pending {
+            break;
+        }
+    }
+    handles
+}
+
+unsafe fn accept_one(lp: &Loop, server: *mut uv_pipe_t) -> Result<ReceivedHandle> {
+    let server_stream = server as *mut uv_stream_t;
+    let handle = match libuv_sys::uv_pipe_pending_type(server) {
+        libuv_sys::UV_TCP => Tcp::new(lp).map(ReceivedHandle::Tcp),
+        libuv_sys::UV_NAMED_PIPE => Pipe::new(lp, false).map(ReceivedHandle::Pipe),
+        libuv_sys::UV_UDP => Udp::new(lp).map(ReceivedHandle::Udp),
+        // For example a plain file, which libuv can receive but not wrap.
+        _ => Err(Error::EINVAL),
+    };
+    match handle {
+        Ok(handle) => {
+            // libuv takes the descriptor and moves on to the next even if this fails.
+            check(libuv_sys::uv_accept(server_stream, handle.as_raw_stream()))?;
+            Ok(handle)
+        }
+        Err(err) => {
+            // The descriptor stays pending until accepted, so it is accepted into a pipe which
+            // is dropped, closing it.
+            if let Ok(pipe) = Pipe::new(lp, false) {
+                libuv_sys::uv_accept(server_stream, pipe.as_raw_stream());
+            }
+            Err(err)
+        }
+    }
+}
+
+impl ReceivedHandle {
+    fn as_raw_stream(&self) -> *mut uv_stream_t {
+        match *self {
+            ReceivedHandle::Tcp(ref tcp) => tcp.as_raw_stream(),
+            ReceivedHandle::Pipe(ref pipe) => pipe.as_raw_stream(),
+            ReceivedHandle::Udp(ref udp) => udp.as_raw() as *mut uv_stream_t,
+        }
+    }
+}
+
+impl Pipe {
+    /// Sends `handle` to the process at the other end of this IPC pipe, along with `data`, which
+    /// must not be empty.  The handle is kept open until the write completes and then dropped, so
+    /// pass an `Rc` to keep using it locally.
+    pub fn write_handle<H, F>(&self, data: Vec<u8>, handle: H, cb: F) -> Result<()>
+        where H: SendHandle,
+              F: FnOnce(Result<()>) + 'static
+    {
+        if data.is_empty() {
+            return Err(Error::EINVAL);
+        }
+        let raw_handle = handle.as_send_handle();
+        unsafe {
+            stream::queue_write(self.as_raw_stream(),
+                                vec![data],
+                                Some((raw_handle, Box::new(handle))),
+                                Box::new(cb))
+        }
+    }
+
+    /// Starts reading from an IPC pipe.  Like `read_start`, but each callback also receives the
+    /// handles which arrived with the data.
+    pub fn read_start_ipc<F>(&self, mut cb: F) -> Result<()>
+        where F: FnMut(Result<IpcRead>) + 'static
+    {
+        let raw = self.as_raw();
+        self.read_start(move |data| {
+            cb(data.map(|data| {
+                IpcRead {
+                    data,
+                    handles: unsafe { accept_pending(raw) },
+                }
+            }))
+        })
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use event_loop::RunMode;
+    use std::cell::RefCell;
+    use std::fs::File;
+    use std::net::TcpListener;
+    use std::os::unix::io::IntoRawFd;
+    use std::os::unix::net::UnixStream;
+
+    fn ipc_pair(lp: &Loop) -> (Pipe, Pipe) {
+        let (a, b) = UnixStream::pair().unwrap();
+        (Pipe::open_ipc(lp, a.into_raw_fd()).unwrap(), Pipe::open_ipc(lp, b.into_raw_fd()).unwrap())
+    }
+
+    #[test]
+    fn empty_message_rejected() {
+        let lp = Loop::new().unwrap();
+        let (a, _b) = ipc_pair(&lp);
+        let tcp = Tcp::new(&lp).unwrap();
+        assert_eq!(a.write_handle(Vec::new(), tcp, |_| ()).err(), Some(Error::EINVAL));
+    }
+
+    #[test]
+    fn tcp_handle_round_trip() {
+        let lp = Loop::new().unwrap();
+        let (a, b) = ipc_pair(&lp);
+        let sock = TcpListener::bind("127.0.0.1:0").unwrap().into_raw_fd();
+        let tcp = Rc::new(Tcp::open(&lp, sock).unwrap());
+
+        a.write_handle(b"listen".to_vec(), tcp.clone(), |res| res.unwrap()).unwrap();
+
+        let received = Rc::new(RefCell::new(None));
+        let received2 = received.clone();
+        let b = Rc::new(RefCell::new(Some(b)));
+        let b2 = b.clone();
+        b.borrow().as_ref().unwrap().read_start_ipc(move |read| {
+            let read = read.unwrap();
+            assert_eq!(read.data, b"listen");
+            let mut handles = read.handles;
+            assert_eq!(handles.len(), 1);
+            *received2.borrow_mut() = handles.pop().unwrap().ok();
+            b2.borrow_mut().take();
+        }).unwrap();
+        drop(tcp);
+        lp.run(RunMode::Default);
+        drop(a);
+
+        let handle = received.borrow_mut().take();
+        match handle {
+            Some(ReceivedHandle::Tcp(_)) => (),
+            _ => panic!("expected a TCP handle"),
+        }
+    }
+
+    #[test]
+    fn unsupported_handle_is_discarded() {
+        let lp = Loop::new().unwrap();
+        let (a, b) = ipc_pair(&lp);
+        // libuv sends any descriptor, but only wraps sockets and pipes on receipt.
+        let file = Pipe::open(&lp, File::open("/dev/null").unwrap().into_raw_fd()).unwrap();
+        a.write_handle(b"file".to_vec(), file, |res| res.unwrap()).unwrap();
+
+        let reads = Rc::new(RefCell::new(Vec::new()));
+        let reads2 = reads.clone();
+        let b = Rc::new(RefCell::new(Some(b)));
+        let b2 = b.clone();
+        b.borrow().as_ref().unwrap().read_start_ipc(move |read| {
+            let read = read.unwrap();
+            let handles = read.handles.iter().map(|handle| handle.as_ref().err().cloned());
+            reads2.borrow_mut().push((read.data.to_vec(), handles.collect::<Vec<_>>()));
+            b2.borrow_mut().take();
+        }).unwrap();
+        lp.run(RunMode::Default);
+        drop(a);
+
+        assert_eq!(*reads.borrow(), [(b"file".to_vec(), vec![Some(Error::EINVAL)])]);
+    }
+}
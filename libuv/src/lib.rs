@@ -1,6 +1,69 @@
+extern crate libc;
 extern crate libuv_sys;
+#[cfg(feature = "bytes")]
+extern crate bytes;
+#[cfg(feature = "mio")]
+extern crate mio;
+extern crate thread_bound;
 use std::ffi::CStr;
 
+pub mod addr;
+pub mod allocator;
+pub mod cluster;
+pub mod dl;
+#[cfg(all(unix, feature = "mio"))]
+pub mod embed;
+pub mod env;
+pub mod error;
+pub mod event_loop;
+mod handle;
+pub mod io;
+pub mod ipc;
+pub mod loop_thread;
+pub mod metrics;
+pub mod pipe;
+pub mod process;
+pub mod process_title;
+#[cfg(unix)]
+pub mod sendfile;
+pub mod signal;
+pub mod stream;
+pub mod sys_info;
+mod task;
+pub mod tcp;
+pub mod time;
+pub mod timer;
+pub mod timer_wheel;
+pub mod tty;
+pub mod udp;
+mod util;
+pub mod write_queue;
+
+pub use addr::SockAddr;
+pub use dl::Library;
+pub use error::{Error, Result};
+pub use event_loop::{HandleInfo, Loop, LoopBuilder, RunMode};
+pub use io::{PipeGuard, PipeStats};
+pub use ipc::{ReceivedHandle, SendHandle};
+pub use loop_thread::LoopThread;
+pub use pipe::{Pipe, PipeListener};
+pub use process::{Process, ProcessOptions, StdioContainer};
+pub use signal::Signal;
+pub use stream::{Stream, StreamTimeouts, WriteBuf};
+pub use tcp::Tcp;
+pub use time::LoopTime;
+pub use timer::Timer;
+pub use timer_wheel::{TimeoutKey, TimerWheel};
+pub use tty::{Tty, TtyMode};
+pub use udp::Udp;
+pub use write_queue::BufferedWriter;
+
+mod sealed {
+    // Implemented only by this crate's handle types, whose `data` fields the default trait
+    // methods rely on, and by buffers whose bytes stay put when they are moved.
+    pub trait Sealed {}
+}
+
 pub fn version_hex() -> u32 {
     unsafe { libuv_sys::uv_version() as u32 }
 }
@@ -0,0 +1,208 @@
+//! Running a loop on a thread of its own.
+//!
+//! `LoopThread` owns a thread which runs a `Loop` until asked to stop.  Other threads hand it
+//! work as closures, which are queued and run on the loop thread when a `uv_async_t` fires.
+
+use error::{check, Error, Result};
+use event_loop::{Loop, RunMode};
+use handle;
+use libc::c_void;
+use libuv_sys::{self, uv_async_t};
+use std::collections::VecDeque;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+type Job = Box<dyn FnOnce(&Loop) + Send>;
+
+struct AsyncPtr(*mut uv_async_t);
+
+// uv_async_send is the one libuv function which may be called from any thread.
+unsafe impl Send for AsyncPtr {}
+
+struct Shared {
+    jobs: Mutex<VecDeque<Job>>,
+    // Cleared before the async handle is closed, so that late callers get an error instead of
+    // signalling a closed handle.
+    wakeup: Mutex<Option<AsyncPtr>>,
+    stop: AtomicBool,
+}
+
+impl Shared {
+    fn signal(&self) -> Result<()> {
+        match *self.wakeup.lock().unwrap() {
+            Some(ref async_) => unsafe { check(libuv_sys::uv_async_send(async_.0)).map(|_| ()) },
+            None => Err(Error::ECANCELED),
+        }
+    }
+}
+
+extern "C" fn async_cb(async_: *mut uv_async_t) {
+    unsafe {
+        let shared = &*((*async_).data as *const Arc<Shared>);
+        // Only the jobs queued so far run, so a job which submits another cannot starve I/O.
+        let jobs: VecDeque<Job> = shared.jobs.lock().unwrap().drain(..).collect();
+        let lp = Loop::from_raw((*async_).loop_);
+        for job in jobs {
+            job(&lp);
+        }
+        if shared.stop.load(Ordering::SeqCst) {
+            lp.stop();
+        }
+    }
+}
+
+// The body of the loop thread.  Reports on `started` once jobs can be accepted, and returns
+// whether any handles were left active.
+fn run(shared: Arc<Shared>, started: mpsc::Sender<Result<()>>) -> bool {
+    let lp = match Loop::new() {
+        Ok(lp) => lp,
+        Err(err) => {
+            let _ = started.send(Err(err));
+            return false;
+        }
+    };
+    unsafe {
+        let async_: *mut uv_async_t = handle::alloc(libuv_sys::UV_ASYNC);
+        if let Err(err) = check(libuv_sys::uv_async_init(lp.as_raw(), async_, async_cb)) {
+            handle::free(async_);
+            let _ = started.send(Err(err));
+            return false;
+        }
+        (*async_).data = Box::into_raw(Box::new(shared.clone())) as *mut c_void;
+        *shared.wakeup.lock().unwrap() = Some(AsyncPtr(async_));
+        let _ = started.send(Ok(()));
+
+        // The async handle keeps the loop alive until `stop`.
+        lp.run(RunMode::Default);
+
+        *shared.wakeup.lock().unwrap() = None;
+        handle::close::<_, Arc<Shared>>(async_);
+        // Jobs which arrived too late are dropped here, on the loop thread.
+        shared.jobs.lock().unwrap().clear();
+        lp.run(RunMode::NoWait);
+        lp.alive()
+    }
+}
+
+/// A loop running on a dedicated thread.  The handle is `Send` and `Sync`, so any thread may
+/// submit work to the loop.  Dropping it stops the loop and waits for the thread to finish.
+pub struct LoopThread {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<bool>>,
+}
+
+// Creating a thread fails for lack of resources, which is `EAGAIN` on Unix.  Windows error
+// codes are not libuv's, so they are reported as that too.
+#[cfg(unix)]
+fn spawn_error(err: io::Error) -> Error {
+    err.raw_os_error().map_or(Error::EAGAIN, |code| Error::from_raw(-code))
+}
+
+#[cfg(windows)]
+fn spawn_error(_err: io::Error) -> Error {
+    Error::EAGAIN
+}
+
+impl LoopThread {
+    /// Starts a thread running a new loop, returning once the loop is ready for work.
+    pub fn spawn() -> Result<LoopThread> {
+        let shared = Arc::new(Shared {
+            jobs: Mutex::new(VecDeque::new()),
+            wakeup: Mutex::new(None),
+            stop: AtomicBool::new(false),
+        });
+        let (tx, rx) = mpsc::channel();
+        let thread_shared = shared.clone();
+        let thread = thread::Builder::new()
+            .name("libuv loop".to_string())
+            .spawn(move || run(thread_shared, tx))
+            .map_err(spawn_error)?;
+        // The sender is only dropped without a message if the thread panicked.
+        let started = rx.recv().unwrap_or(Err(Error::ECANCELED));
+        if let Err(err) = started {
+            let _ = thread.join();
+            return Err(err);
+        }
+        Ok(LoopThread {
+            shared,
+            thread: Some(thread),
+        })
+    }
+
+    /// Queues `f` to run on the loop thread with the loop.  Closures run in the order they
+    /// were submitted.  Fails with `ECANCELED` once the loop has stopped, in which case `f` is
+    /// dropped.
+    pub fn submit<F>(&self, f: F) -> Result<()>
+        where F: FnOnce(&Loop) + Send + 'static
+    {
+        // Queued under the wakeup lock, so that the loop thread cannot finish in between.
+        let wakeup = self.shared.wakeup.lock().unwrap();
+        match *wakeup {
+            Some(ref async_) => {
+                self.shared.jobs.lock().unwrap().push_back(Box::new(f));
+                unsafe { check(libuv_sys::uv_async_send(async_.0)).map(|_| ()) }
+            }
+            None => Err(Error::ECANCELED),
+        }
+    }
+
+    /// Asks the loop to stop with `uv_stop` once the closures already submitted have run.
+    /// Handles which are still active are left as they are.
+    pub fn stop(&self) {
+        self.shared.stop.store(true, Ordering::SeqCst);
+        let _ = self.shared.signal();
+    }
+
+    /// Waits for the loop thread to finish, which it does after `stop`.  Returns true if
+    /// handles created by submitted closures were still active, or the panic which ended the
+    /// thread.
+    pub fn join(mut self) -> thread::Result<bool> {
+        self.thread.take().unwrap().join()
+    }
+}
+
+impl Drop for LoopThread {
+    fn drop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            self.stop();
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_jobs_on_loop_thread() {
+        let loop_thread = LoopThread::spawn().unwrap();
+        let (tx, rx) = mpsc::channel();
+        for i in 0..3 {
+            let tx = tx.clone();
+            loop_thread.submit(move |_| {
+                    tx.send((i, thread::current().id())).unwrap();
+                })
+                .unwrap();
+        }
+        for i in 0..3 {
+            let (j, id) = rx.recv().unwrap();
+            assert_eq!(i, j);
+            assert!(id != thread::current().id());
+        }
+        loop_thread.stop();
+        assert_eq!(loop_thread.join().ok(), Some(false));
+    }
+
+    #[test]
+    fn signal_after_join_fails() {
+        let loop_thread = LoopThread::spawn().unwrap();
+        let shared = loop_thread.shared.clone();
+        drop(loop_thread);
+        assert_eq!(shared.signal(), Err(Error::ECANCELED));
+        assert!(shared.jobs.lock().unwrap().is_empty());
+    }
+}
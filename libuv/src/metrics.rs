@@ -0,0 +1,218 @@
+//! Periodic sampling of host and process metrics.
+//!
+//! A `MetricsSampler` reads the `sys_info` counters on a timer and turns the CPU times, which
+//! only ever grow, into utilization over each interval.
+
+use error::Result;
+use event_loop::Loop;
+use std::sync::mpsc::{self, Receiver};
+use std::time::Duration;
+use sys_info::{self, CpuTimes, ResourceUsage};
+use time;
+use timer::Timer;
+
+/// How busy one CPU was during an interval.
+#[derive(Clone,Copy,Debug,Default,PartialEq)]
+pub struct CpuUsage {
+    /// Milliseconds spent in each mode during the interval.
+    pub times: CpuTimes,
+    /// The percentage of the interval spent doing anything but idling, from 0 to 100.
+    pub utilization: f64,
+}
+
+impl CpuUsage {
+    fn between(before: &CpuTimes, after: &CpuTimes) -> CpuUsage {
+        let times = CpuTimes {
+            user: after.user.saturating_sub(before.user),
+            nice: after.nice.saturating_sub(before.nice),
+            sys: after.sys.saturating_sub(before.sys),
+            idle: after.idle.saturating_sub(before.idle),
+            irq: after.irq.saturating_sub(before.irq),
+        };
+        let total = times.total();
+        let utilization = if total == 0 {
+            0.0
+        } else {
+            100.0 * (total - times.idle) as f64 / total as f64
+        };
+        CpuUsage { times, utilization }
+    }
+}
+
+/// The metrics collected at the end of one interval.
+#[derive(Clone,Debug,PartialEq)]
+pub struct MetricsSnapshot {
+    /// When the sample was taken, as returned by `time::hrtime`.
+    pub timestamp: Duration,
+    /// The time since the previous sample.
+    pub elapsed: Duration,
+    /// The usage of each CPU since the previous sample.
+    pub cpus: Vec<CpuUsage>,
+    /// The usage of all CPUs together.
+    pub cpu_total: CpuUsage,
+    /// The resident set size of this process, in bytes.
+    pub rss: usize,
+    /// The resource usage of this process.
+    pub rusage: ResourceUsage,
+    /// The 1, 5 and 15 minute load averages.
+    pub loadavg: [f64; 3],
+    /// Free memory, in bytes.
+    pub free_memory: u64,
+    /// Total memory, in bytes.
+    pub total_memory: u64,
+}
+
+// The counters a snapshot is computed against.
+struct Baseline {
+    timestamp: Duration,
+    cpus: Vec<CpuTimes>,
+}
+
+impl Baseline {
+    fn take() -> Result<Baseline> {
+        Ok(Baseline {
+            timestamp: time::hrtime(),
+            cpus: sys_info::cpu_info()?.into_iter().map(|cpu| cpu.times).collect(),
+        })
+    }
+
+    // Replaces the baseline with the current counters and returns the usage in between.
+    fn sample(&mut self) -> Result<MetricsSnapshot> {
+        let next = Baseline::take()?;
+        // CPUs which appeared since the previous sample are measured from zero; ones which
+        // disappeared are left out.
+        let zero = CpuTimes::default();
+        let cpus: Vec<_> = next.cpus
+            .iter()
+            .enumerate()
+            .map(|(i, after)| CpuUsage::between(self.cpus.get(i).unwrap_or(&zero), after))
+            .collect();
+        let sum = |times: &[CpuTimes]| {
+            times.iter().fold(CpuTimes::default(), |acc, t| {
+                CpuTimes {
+                    user: acc.user + t.user,
+                    nice: acc.nice + t.nice,
+                    sys: acc.sys + t.sys,
+                    idle: acc.idle + t.idle,
+                    irq: acc.irq + t.irq,
+                }
+            })
+        };
+        let deltas: Vec<_> = cpus.iter().map(|cpu| cpu.times).collect();
+        let snapshot = MetricsSnapshot {
+            timestamp: next.timestamp,
+            elapsed: next.timestamp.saturating_sub(self.timestamp),
+            cpu_total: CpuUsage::between(&CpuTimes::default(), &sum(&deltas)),
+            cpus,
+            rss: sys_info::resident_set_memory()?,
+            rusage: sys_info::getrusage()?,
+            loadavg: sys_info::loadavg(),
+            free_memory: sys_info::free_memory(),
+            total_memory: sys_info::total_memory(),
+        };
+        *self = next;
+        Ok(snapshot)
+    }
+}
+
+/// Collects a `MetricsSnapshot` at a fixed interval.  Sampling stops when the sampler is
+/// dropped or stopped.
+pub struct MetricsSampler {
+    timer: Timer,
+}
+
+impl MetricsSampler {
+    /// Starts sampling every `interval`.  `cb` receives each snapshot, or the error which
+    /// prevented one from being taken; the first arrives after one interval.
+    pub fn start<F>(lp: &Loop, interval: Duration, mut cb: F) -> Result<MetricsSampler>
+        where F: FnMut(Result<MetricsSnapshot>) + 'static
+    {
+        let mut baseline = Baseline::take()?;
+        let timer = Timer::new(lp)?;
+        timer.start(interval, interval, move || cb(baseline.sample()))?;
+        Ok(MetricsSampler { timer })
+    }
+
+    /// Starts sampling every `interval` and sends the snapshots to the returned receiver, which
+    /// may be moved to another thread.  Snapshots are discarded once the receiver is dropped.
+    pub fn channel(lp: &Loop,
+                   interval: Duration)
+                   -> Result<(MetricsSampler, Receiver<Result<MetricsSnapshot>>)> {
+        let (tx, rx) = mpsc::channel();
+        let sampler = MetricsSampler::start(lp, interval, move |snapshot| {
+            let _ = tx.send(snapshot);
+        })?;
+        Ok((sampler, rx))
+    }
+
+    /// Stops sampling.
+    pub fn stop(&self) -> Result<()> {
+        self.timer.stop()
+    }
+
+    /// Returns the timer driving the sampler, for example to change its interval.
+    pub fn timer(&self) -> &Timer {
+        &self.timer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use event_loop::RunMode;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn usage_between_samples() {
+        let before = CpuTimes {
+            user: 100,
+            nice: 0,
+            sys: 50,
+            idle: 1000,
+            irq: 0,
+        };
+        let after = CpuTimes {
+            user: 130,
+            nice: 0,
+            sys: 60,
+            idle: 1060,
+            irq: 0,
+        };
+        let usage = CpuUsage::between(&before, &after);
+        assert_eq!(usage.times.total(), 100);
+        assert_eq!(usage.utilization, 40.0);
+        assert_eq!(CpuUsage::between(&after, &after).utilization, 0.0);
+    }
+
+    #[test]
+    fn samples_until_stopped() {
+        let lp = Loop::new().unwrap();
+        let snapshots = Rc::new(RefCell::new(Vec::new()));
+        let snapshots2 = snapshots.clone();
+        let sampler = Rc::new(RefCell::new(None));
+        let sampler2 = sampler.clone();
+        *sampler.borrow_mut() = Some(MetricsSampler::start(&lp,
+                                                           Duration::from_millis(5),
+                                                           move |snapshot| {
+                snapshots2.borrow_mut().push(snapshot.unwrap());
+                if snapshots2.borrow().len() == 2 {
+                    sampler2.borrow_mut().take();
+                }
+            })
+            .unwrap());
+        lp.run(RunMode::Default);
+
+        let snapshots = snapshots.borrow();
+        assert_eq!(snapshots.len(), 2);
+        let cpus = sys_info::cpu_info().unwrap().len();
+        for snapshot in snapshots.iter() {
+            assert_eq!(snapshot.cpus.len(), cpus);
+            assert!(snapshot.elapsed >= Duration::from_millis(4));
+            assert!(snapshot.cpu_total.utilization >= 0.0);
+            assert!(snapshot.cpu_total.utilization <= 100.0);
+            assert!(snapshot.rss > 0);
+        }
+        assert!(snapshots[1].timestamp > snapshots[0].timestamp);
+    }
+}
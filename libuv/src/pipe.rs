@@ -0,0 +1,288 @@
+//! Local streams: Unix domain sockets, or named pipes on Windows.
+
+use error::{check, Result};
+use event_loop::Loop;
+use handle;
+use libc::{c_int, c_void};
+use libuv_sys::{self, uv_connect_t, uv_file, uv_pipe_t, uv_stream_t};
+use sealed::Sealed;
+use std::path::{Path, PathBuf};
+use stream::{self, Stream};
+use util::{bytes_to_path, fetch_bytes, path_to_cstring};
+
+unsafe fn new_pipe(lp: &Loop, ipc: bool) -> Result<*mut uv_pipe_t> {
+    let uv_loop = lp.checked_raw()?;
+    let raw: *mut uv_pipe_t = handle::alloc(libuv_sys::UV_NAMED_PIPE);
+    if let Err(err) = check(libuv_sys::uv_pipe_init(uv_loop, raw, ipc as c_int)) {
+        handle::free(raw);
+        return Err(err);
+    }
+    stream::attach(raw);
+    Ok(raw)
+}
+
+unsafe fn sockname(raw: *mut uv_pipe_t) -> Result<PathBuf> {
+    fetch_bytes(|buf, size| libuv_sys::uv_pipe_getsockname(raw, buf, size)).map(bytes_to_path)
+}
+
+/// A connected pipe.
+pub struct Pipe {
+    raw: *mut uv_pipe_t,
+    lp: Loop,
+}
+
+struct ConnectReq {
+    pipe: Pipe,
+    cb: Box<dyn FnOnce(Result<Pipe>)>,
+}
+
+extern "C" fn connect_cb(req: *mut uv_connect_t, status: c_int) {
+    unsafe {
+        let ConnectReq { pipe, cb } = *Box::from_raw((*req).data as *mut ConnectReq);
+        handle::free(req);
+        cb(check(status).map(|_| pipe));
+    }
+}
+
+impl Pipe {
+    /// Connects to the pipe or socket named `name`.  The connection is made asynchronously and
+    /// `cb` receives either the connected pipe or the reason the connection failed.
+    pub fn connect<F>(lp: &Loop, name: &Path, cb: F) -> Result<()>
+        where F: FnOnce(Result<Pipe>) + 'static
+    {
+        let name = path_to_cstring(name)?;
+        let pipe = Pipe::new(lp, false)?;
+        unsafe {
+            let raw = pipe.raw;
+            let req: *mut uv_connect_t = handle::alloc_req(libuv_sys::UV_CONNECT);
+            (*req).data = Box::into_raw(Box::new(ConnectReq {
+                pipe,
+                cb: Box::new(cb),
+            })) as *mut c_void;
+            libuv_sys::uv_pipe_connect(req, raw, name.as_ptr(), connect_cb);
+        }
+        Ok(())
+    }
+
+    /// Creates a pipe which is not yet connected.  Pipes with `ipc` set can carry handles as
+    /// well as data; see `write_handle` and `read_start_ipc`.
+    pub fn new(lp: &Loop, ipc: bool) -> Result<Pipe> {
+        unsafe {
+            Ok(Pipe {
+                raw: new_pipe(lp, ipc)?,
+                lp: lp.clone(),
+            })
+        }
+    }
+
+    /// Wraps an existing file descriptor or handle, such as one end of a socket pair.
+    pub fn open(lp: &Loop, file: uv_file) -> Result<Pipe> {
+        let pipe = Pipe::new(lp, false)?;
+        unsafe {
+            check(libuv_sys::uv_pipe_open(pipe.raw, file))?;
+        }
+        Ok(pipe)
+    }
+
+    /// Wraps an existing file descriptor or handle as an IPC pipe.  The other end must also be
+    /// used as an IPC pipe, as libuv frames the data it sends.
+    pub fn open_ipc(lp: &Loop, file: uv_file) -> Result<Pipe> {
+        let pipe = Pipe::new(lp, true)?;
+        unsafe {
+            check(libuv_sys::uv_pipe_open(pipe.raw, file))?;
+        }
+        Ok(pipe)
+    }
+
+    /// Returns the underlying `uv_pipe_t`.
+    pub fn as_raw(&self) -> *mut uv_pipe_t {
+        self.raw
+    }
+
+    /// Returns the loop this pipe belongs to.
+    pub fn get_loop(&self) -> &Loop {
+        &self.lp
+    }
+
+    /// Returns the name of the local end of the pipe.
+    pub fn sockname(&self) -> Result<PathBuf> {
+        unsafe { sockname(self.raw) }
+    }
+
+    /// Returns the name of the pipe's peer.
+    pub fn peername(&self) -> Result<PathBuf> {
+        unsafe {
+            fetch_bytes(|buf, size| libuv_sys::uv_pipe_getpeername(self.raw, buf, size))
+                .map(bytes_to_path)
+        }
+    }
+}
+
+impl Sealed for Pipe {}
+
+impl Stream for Pipe {
+    fn as_raw_stream(&self) -> *mut uv_stream_t {
+        self.raw as *mut uv_stream_t
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        unsafe { stream::close(self.raw) }
+    }
+}
+
+/// A pipe bound to a name and accepting connections.
+pub struct PipeListener {
+    raw: *mut uv_pipe_t,
+    lp: Loop,
+}
+
+impl PipeListener {
+    /// Binds a new pipe to `name`, which is a filesystem path on Unix and of the form
+    /// `\\.\pipe\name` on Windows.
+    pub fn bind(lp: &Loop, name: &Path) -> Result<PipeListener> {
+        let name = path_to_cstring(name)?;
+        unsafe {
+            let listener = PipeListener {
+                raw: new_pipe(lp, false)?,
+                lp: lp.clone(),
+            };
+            check(libuv_sys::uv_pipe_bind(listener.raw, name.as_ptr()))?;
+            Ok(listener)
+        }
+    }
+
+    /// Returns the loop this listener belongs to.
+    pub fn get_loop(&self) -> &Loop {
+        &self.lp
+    }
+
+    /// Returns the name the listener is bound to.
+    pub fn sockname(&self) -> Result<PathBuf> {
+        unsafe { sockname(self.raw) }
+    }
+
+    /// Sets the number of pipe instances kept ready for incoming connections.  This only has an
+    /// effect on Windows and must be called before `listen`.
+    pub fn pending_instances(&self, count: i32) {
+        unsafe { libuv_sys::uv_pipe_pending_instances(self.raw, count) }
+    }
+
+    /// Starts accepting connections.  `cb` receives each accepted pipe, or the error which
+    /// prevented a connection from being accepted.
+    pub fn listen<F>(&self, backlog: i32, mut cb: F) -> Result<()>
+        where F: FnMut(Result<Pipe>) + 'static
+    {
+        let raw = self.raw;
+        unsafe {
+            stream::listen(raw as *mut uv_stream_t,
+                           backlog,
+                           Box::new(move |status| cb(status.and_then(|()| accept(raw)))))
+        }
+    }
+}
+
+// Only called from the connection callback, while the listener is necessarily still open.
+unsafe fn accept(server: *mut uv_pipe_t) -> Result<Pipe> {
+    let lp = Loop::from_raw((*server).loop_);
+    let client = Pipe {
+        raw: new_pipe(&lp, false)?,
+        lp,
+    };
+    check(libuv_sys::uv_accept(server as *mut uv_stream_t, client.raw as *mut uv_stream_t))?;
+    Ok(client)
+}
+
+impl Drop for PipeListener {
+    fn drop(&mut self) {
+        unsafe { stream::close(self.raw) }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use error::Error;
+    use event_loop::RunMode;
+    use std::cell::RefCell;
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::rc::Rc;
+
+    fn socket_path(tag: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("libuv-rs-{}-{}.sock", tag, process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn bind_reports_sockname() {
+        let lp = Loop::new().unwrap();
+        let path = socket_path("sockname");
+        let listener = PipeListener::bind(&lp, &path).unwrap();
+        assert_eq!(listener.sockname().unwrap(), path);
+        drop(listener);
+        lp.run(RunMode::Default);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn connect_missing_path_fails() {
+        let lp = Loop::new().unwrap();
+        let result = Rc::new(RefCell::new(None));
+        let result2 = result.clone();
+        Pipe::connect(&lp, &socket_path("missing"), move |res| {
+                *result2.borrow_mut() = Some(res.err());
+            })
+            .unwrap();
+        lp.run(RunMode::Default);
+        let err = result.borrow_mut().take().unwrap().unwrap();
+        assert_eq!(err.name(), "ENOENT");
+    }
+
+    #[test]
+    fn echo_round_trip() {
+        let lp = Loop::new().unwrap();
+        let path = socket_path("echo");
+        let listener = Rc::new(RefCell::new(Some(PipeListener::bind(&lp, &path).unwrap())));
+        let received = Rc::new(RefCell::new(Vec::new()));
+
+        let server_conn = Rc::new(RefCell::new(None));
+        let server_conn2 = server_conn.clone();
+        let listener2 = listener.clone();
+        let received2 = received.clone();
+        listener.borrow().as_ref().unwrap().listen(1, move |conn| {
+            let conn = conn.unwrap();
+            let received3 = received2.clone();
+            let server_conn3 = server_conn2.clone();
+            conn.read_start(move |data| match data {
+                    Ok(data) => received3.borrow_mut().extend_from_slice(data),
+                    Err(err) => {
+                        assert_eq!(err, Error::EOF);
+                        server_conn3.borrow_mut().take();
+                    }
+                })
+                .unwrap();
+            *server_conn2.borrow_mut() = Some(conn);
+            listener2.borrow_mut().take();
+        }).unwrap();
+
+        Pipe::connect(&lp, &path, |conn| {
+                let conn = Rc::new(conn.unwrap());
+                let conn2 = conn.clone();
+                conn.write(b"hello".to_vec(), |res| res.unwrap()).unwrap();
+                conn.shutdown(move |res| {
+                        res.unwrap();
+                        drop(conn2);
+                    })
+                    .unwrap();
+            })
+            .unwrap();
+
+        lp.run(RunMode::Default);
+        assert_eq!(&received.borrow()[..], b"hello");
+        fs::remove_file(&path).unwrap();
+    }
+}
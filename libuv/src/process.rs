@@ -0,0 +1,397 @@
+//! Child processes.
+
+use error::{check, Error, Result};
+use event_loop::Loop;
+use handle;
+use libc::{c_char, c_int, c_uint, c_void, int64_t};
+use libuv_sys::{self, uv_file, uv_process_options_t, uv_process_t, uv_stdio_container_t};
+use pipe::Pipe;
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::{CString, OsStr, OsString};
+use std::path::Path;
+use std::ptr;
+use stream::Stream;
+use util::{os_str_to_cstring, path_to_cstring};
+
+type ExitCallback = Box<dyn FnOnce(i64, i32)>;
+
+/// What a child's file descriptor is connected to.
+pub enum StdioContainer<'a> {
+    /// Nothing.  On Unix, descriptors 0 to 2 are connected to `/dev/null` instead.
+    Ignore,
+    /// The same file as one of this process's file descriptors.
+    InheritFd(uv_file),
+    /// The same file as an open stream, such as a pipe or socket.
+    InheritStream(&'a dyn Stream),
+    /// A new pipe whose other end is `pipe`, which must have been created with `Pipe::new` and
+    /// not yet opened or connected.  `readable` and `writable` describe the child's end.
+    CreatePipe {
+        /// The parent's end of the pipe.
+        pipe: &'a Pipe,
+        /// Whether the child can read from the pipe.
+        readable: bool,
+        /// Whether the child can write to the pipe.
+        writable: bool,
+    },
+}
+
+/// How to start a child process, in the style of `std::process::Command`.  By default the child
+/// inherits the environment and working directory of this process and has no file descriptors
+/// connected.
+pub struct ProcessOptions<'a> {
+    file: OsString,
+    args: Vec<OsString>,
+    env_clear: bool,
+    env: Vec<(OsString, OsString)>,
+    cwd: Option<CString>,
+    flags: c_uint,
+    uid: libuv_sys::uv_uid_t,
+    gid: libuv_sys::uv_gid_t,
+    stdio: Vec<StdioContainer<'a>>,
+    invalid: bool,
+}
+
+impl<'a> ProcessOptions<'a> {
+    /// Runs `file`, which is looked up in `PATH` if it does not contain a path separator.  It is
+    /// also passed as the first argument.
+    pub fn new<S: AsRef<OsStr>>(file: S) -> ProcessOptions<'a> {
+        let file = file.as_ref().to_owned();
+        ProcessOptions {
+            args: vec![file.clone()],
+            file,
+            env_clear: false,
+            env: Vec::new(),
+            cwd: None,
+            flags: 0,
+            uid: 0,
+            gid: 0,
+            stdio: Vec::new(),
+            invalid: false,
+        }
+    }
+
+    /// Adds an argument.
+    pub fn arg<S: AsRef<OsStr>>(&mut self, arg: S) -> &mut ProcessOptions<'a> {
+        self.args.push(arg.as_ref().to_owned());
+        self
+    }
+
+    /// Adds several arguments.
+    pub fn args<I, S>(&mut self, args: I) -> &mut ProcessOptions<'a>
+        where I: IntoIterator<Item = S>,
+              S: AsRef<OsStr>
+    {
+        for arg in args {
+            self.arg(arg);
+        }
+        self
+    }
+
+    /// Sets an environment variable for the child.
+    pub fn env<K, V>(&mut self, key: K, value: V) -> &mut ProcessOptions<'a>
+        where K: AsRef<OsStr>,
+              V: AsRef<OsStr>
+    {
+        self.env.push((key.as_ref().to_owned(), value.as_ref().to_owned()));
+        self
+    }
+
+    /// Starts the child with an empty environment, apart from variables set with `env`.
+    pub fn env_clear(&mut self) -> &mut ProcessOptions<'a> {
+        self.env_clear = true;
+        self.env.clear();
+        self
+    }
+
+    /// Sets the child's working directory.
+    pub fn cwd(&mut self, dir: &Path) -> &mut ProcessOptions<'a> {
+        match path_to_cstring(dir) {
+            Ok(dir) => self.cwd = Some(dir),
+            Err(_) => self.invalid = true,
+        }
+        self
+    }
+
+    /// Connects the child's file descriptor `fd` to `container`.  Descriptors below `fd` which
+    /// have not been set are ignored.
+    pub fn stdio(&mut self, fd: usize, container: StdioContainer<'a>) -> &mut ProcessOptions<'a> {
+        while self.stdio.len() <= fd {
+            self.stdio.push(StdioContainer::Ignore);
+        }
+        self.stdio[fd] = container;
+        self
+    }
+
+    /// Starts the child in its own process group, so that it can outlive this process.
+    pub fn detached(&mut self, detached: bool) -> &mut ProcessOptions<'a> {
+        self.set_flag(libuv_sys::UV_PROCESS_DETACHED as c_uint, detached)
+    }
+
+    /// Runs the child as the given user.
+    #[cfg(unix)]
+    pub fn uid(&mut self, uid: u32) -> &mut ProcessOptions<'a> {
+        self.uid = uid as libuv_sys::uv_uid_t;
+        self.set_flag(libuv_sys::UV_PROCESS_SETUID as c_uint, true)
+    }
+
+    /// Runs the child as the given group.
+    #[cfg(unix)]
+    pub fn gid(&mut self, gid: u32) -> &mut ProcessOptions<'a> {
+        self.gid = gid as libuv_sys::uv_gid_t;
+        self.set_flag(libuv_sys::UV_PROCESS_SETGID as c_uint, true)
+    }
+
+    fn set_flag(&mut self, flag: c_uint, set: bool) -> &mut ProcessOptions<'a> {
+        if set {
+            self.flags |= flag;
+        } else {
+            self.flags &= !flag;
+        }
+        self
+    }
+
+    // Returns `None` to inherit this process's environment unchanged.
+    fn environment(&self) -> Result<Option<Vec<CString>>> {
+        if !self.env_clear && self.env.is_empty() {
+            return Ok(None);
+        }
+        let mut vars = BTreeMap::new();
+        if !self.env_clear {
+            vars.extend(env::vars_os());
+        }
+        vars.extend(self.env.iter().cloned());
+        let mut env = Vec::with_capacity(vars.len());
+        for (key, value) in vars {
+            let mut var = key;
+            var.push("=");
+            var.push(value);
+            env.push(os_str_to_cstring(&var)?);
+        }
+        Ok(Some(env))
+    }
+
+    fn stdio_containers(&self) -> Vec<uv_stdio_container_t> {
+        self.stdio
+            .iter()
+            .map(|container| {
+                let mut raw = uv_stdio_container_t {
+                    flags: libuv_sys::UV_IGNORE as c_int,
+                    stream: ptr::null_mut(),
+                };
+                match *container {
+                    StdioContainer::Ignore => (),
+                    StdioContainer::InheritFd(fd) => {
+                        raw.flags = libuv_sys::UV_INHERIT_FD as c_int;
+                        // `stream` is a union with the descriptor.
+                        unsafe {
+                            *(&mut raw.stream as *mut _ as *mut c_int) = fd;
+                        }
+                    }
+                    StdioContainer::InheritStream(stream) => {
+                        raw.flags = libuv_sys::UV_INHERIT_STREAM as c_int;
+                        raw.stream = stream.as_raw_stream();
+                    }
+                    StdioContainer::CreatePipe { pipe, readable, writable } => {
+                        raw.flags = libuv_sys::UV_CREATE_PIPE as c_int;
+                        if readable {
+                            raw.flags |= libuv_sys::UV_READABLE_PIPE as c_int;
+                        }
+                        if writable {
+                            raw.flags |= libuv_sys::UV_WRITABLE_PIPE as c_int;
+                        }
+                        raw.stream = pipe.as_raw_stream();
+                    }
+                }
+                raw
+            })
+            .collect()
+    }
+}
+
+// Builds a NULL-terminated array of pointers into `strings`, which must outlive it.
+fn c_array(strings: &[CString]) -> Vec<*mut c_char> {
+    let mut array: Vec<_> = strings.iter().map(|s| s.as_ptr() as *mut c_char).collect();
+    array.push(ptr::null_mut());
+    array
+}
+
+/// A child process.
+pub struct Process {
+    raw: *mut uv_process_t,
+    lp: Loop,
+}
+
+impl Process {
+    /// Starts a child process.  `exit_cb` receives its exit status and, if it was killed, the
+    /// signal which killed it; both are zero for a successful exit.
+    pub fn spawn<F>(lp: &Loop, options: &ProcessOptions, cb: F) -> Result<Process>
+        where F: FnOnce(i64, i32) + 'static
+    {
+        if options.invalid {
+            return Err(Error::EINVAL);
+        }
+        let file = os_str_to_cstring(&options.file)?;
+        let args = options.args
+            .iter()
+            .map(|arg| os_str_to_cstring(arg))
+            .collect::<Result<Vec<_>>>()?;
+        let env = options.environment()?;
+        let mut arg_ptrs = c_array(&args);
+        let mut env_ptrs = env.as_ref().map(|env| c_array(env));
+        let mut stdio = options.stdio_containers();
+
+        let raw_options = uv_process_options_t {
+            exit_cb,
+            file: file.as_ptr(),
+            args: arg_ptrs.as_mut_ptr(),
+            env: env_ptrs.as_mut().map_or(ptr::null_mut(), |env| env.as_mut_ptr()),
+            cwd: options.cwd.as_ref().map_or(ptr::null(), |cwd| cwd.as_ptr()),
+            flags: options.flags,
+            stdio_count: stdio.len() as c_int,
+            stdio: stdio.as_mut_ptr(),
+            uid: options.uid,
+            gid: options.gid,
+        };
+        unsafe {
+            let uv_loop = lp.checked_raw()?;
+            let raw: *mut uv_process_t = handle::alloc(libuv_sys::UV_PROCESS);
+            let cb: ExitCallback = Box::new(cb);
+            (*raw).data = Box::into_raw(Box::new(Cell::new(Some(cb)))) as *mut c_void;
+            let process = Process {
+                raw,
+                lp: lp.clone(),
+            };
+            // A failed spawn still initializes the handle, which dropping `process` closes.
+            check(libuv_sys::uv_spawn(uv_loop, raw, &raw_options))?;
+            Ok(process)
+        }
+    }
+
+    /// Returns the underlying `uv_process_t`.
+    pub fn as_raw(&self) -> *mut uv_process_t {
+        self.raw
+    }
+
+    /// Returns the loop this process belongs to.
+    pub fn get_loop(&self) -> &Loop {
+        &self.lp
+    }
+
+    /// Returns the child's process ID.
+    pub fn pid(&self) -> i32 {
+        unsafe { (*self.raw).pid }
+    }
+
+    /// Sends `signum` to the child.  On Windows, `SIGTERM`, `SIGKILL` and `SIGINT` terminate
+    /// it.
+    pub fn kill(&self, signum: i32) -> Result<()> {
+        unsafe {
+            // Once the handle is closed the child may have been reaped and its PID reused.
+            handle::ensure_open(self.raw)?;
+            check(libuv_sys::uv_process_kill(self.raw, signum)).map(|_| ())
+        }
+    }
+}
+
+extern "C" fn exit_cb(raw: *mut uv_process_t, exit_status: int64_t, term_signal: c_int) {
+    unsafe {
+        let cb = &*((*raw).data as *const Cell<Option<ExitCallback>>);
+        if let Some(cb) = cb.take() {
+            cb(exit_status, term_signal);
+        }
+    }
+}
+
+// Closing the handle does not affect the child, but its exit is no longer reported.
+impl Drop for Process {
+    fn drop(&mut self) {
+        unsafe { handle::close::<_, Cell<Option<ExitCallback>>>(self.raw) }
+    }
+}
+
+/// Sends `signum` to the process with ID `pid`.
+pub fn kill(pid: i32, signum: i32) -> Result<()> {
+    unsafe { check(libuv_sys::uv_kill(pid, signum)).map(|_| ()) }
+}
+
+/// Marks this process's inherited file descriptors close-on-exec, so that children only receive
+/// those passed explicitly.  Call it early, before other threads start.
+pub fn disable_stdio_inheritance() {
+    unsafe { libuv_sys::uv_disable_stdio_inheritance() }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use event_loop::RunMode;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    type Status = Rc<Cell<Option<(i64, i32)>>>;
+
+    fn spawn(lp: &Loop, options: &ProcessOptions) -> (Process, Status) {
+        let status = Rc::new(Cell::new(None));
+        let status2 = status.clone();
+        let process = Process::spawn(lp, options, move |code, signal| {
+                status2.set(Some((code, signal)));
+            })
+            .unwrap();
+        assert!(process.pid() > 0);
+        (process, status)
+    }
+
+    #[test]
+    fn reports_exit_status() {
+        let lp = Loop::new().unwrap();
+        let (_process, status) = spawn(&lp, ProcessOptions::new("sh").args(["-c", "exit 3"]));
+        lp.run(RunMode::Default);
+        assert_eq!(status.get(), Some((3, 0)));
+    }
+
+    #[test]
+    fn reads_child_output() {
+        let lp = Loop::new().unwrap();
+        let pipe = Rc::new(Pipe::new(&lp, false).unwrap());
+        let mut options = ProcessOptions::new("sh");
+        options.args(["-c", "echo \"$GREETING\""])
+            .env("GREETING", "hello")
+            .stdio(1,
+                   StdioContainer::CreatePipe {
+                       pipe: &pipe,
+                       readable: false,
+                       writable: true,
+                   });
+        let (_process, status) = spawn(&lp, &options);
+
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let output2 = output.clone();
+        let pipe2 = pipe.clone();
+        pipe.read_start(move |data| match data {
+                Ok(data) => output2.borrow_mut().extend_from_slice(data),
+                Err(_) => pipe2.read_stop().unwrap(),
+            })
+            .unwrap();
+        lp.run(RunMode::Default);
+        assert_eq!(status.get(), Some((0, 0)));
+        assert_eq!(&output.borrow()[..], b"hello\n");
+    }
+
+    #[test]
+    fn missing_program_fails() {
+        let lp = Loop::new().unwrap();
+        let options = ProcessOptions::new("/nonexistent/libuv-rs-test");
+        let err = Process::spawn(&lp, &options, |_, _| ()).err().unwrap();
+        assert_eq!(err.name(), "ENOENT");
+        assert!(!lp.run(RunMode::Default));
+    }
+
+    #[test]
+    fn interior_nul_is_invalid() {
+        let lp = Loop::new().unwrap();
+        let mut options = ProcessOptions::new("sh");
+        options.arg("a\0b");
+        assert_eq!(Process::spawn(&lp, &options, |_, _| ()).err(), Some(Error::EINVAL));
+    }
+}
@@ -0,0 +1,179 @@
+//! The process title shown by tools such as `ps`.
+//!
+//! On Unix, libuv sets the title by overwriting the memory holding the original command line,
+//! which `uv_setup_args` must first take over.  Rust programs never see that memory, so `init`
+//! recovers it where the platform allows: from the arguments glibc passes to initializers on
+//! Linux, and from `_NSGetArgv` on macOS.  Elsewhere on Unix `init` fails with `ENOSYS`.
+//!
+//! `set` and `get` call `init` themselves, so the ordering libuv requires cannot be violated.
+//! Once the title has been set, the original command line is gone; `std::env::args` reads the
+//! same memory, so use `args` instead.
+
+use allocator;
+use error::{check, Error, Result};
+use libc::{c_char, size_t};
+use libuv_sys;
+use std::ffi::{CStr, CString, OsString};
+use std::sync::{Mutex, MutexGuard};
+use util::bytes_to_path;
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+mod argv {
+    use libc::{c_char, c_int};
+    use std::ptr;
+    use std::sync::atomic::{AtomicIsize, AtomicPtr, Ordering};
+
+    static ARGC: AtomicIsize = AtomicIsize::new(0);
+    static ARGV: AtomicPtr<*mut c_char> = AtomicPtr::new(ptr::null_mut());
+
+    // glibc passes `argc`, `argv` and `envp` to functions in `.init_array`.
+    #[link_section = ".init_array"]
+    #[used]
+    static CAPTURE: extern "C" fn(c_int, *mut *mut c_char, *mut *mut c_char) = capture;
+
+    extern "C" fn capture(argc: c_int, argv: *mut *mut c_char, _envp: *mut *mut c_char) {
+        ARGC.store(argc as isize, Ordering::Relaxed);
+        ARGV.store(argv, Ordering::Relaxed);
+    }
+
+    pub fn get() -> Option<(c_int, *mut *mut c_char)> {
+        let argv = ARGV.load(Ordering::Relaxed);
+        if argv.is_null() {
+            None
+        } else {
+            Some((ARGC.load(Ordering::Relaxed) as c_int, argv))
+        }
+    }
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+mod argv {
+    use libc::{c_char, c_int};
+
+    extern "C" {
+        fn _NSGetArgc() -> *mut c_int;
+        fn _NSGetArgv() -> *mut *mut *mut c_char;
+    }
+
+    pub fn get() -> Option<(c_int, *mut *mut c_char)> {
+        unsafe { Some((*_NSGetArgc(), *_NSGetArgv())) }
+    }
+}
+
+// libuv does not need the command line on Windows, where it sets the console title.
+#[cfg(windows)]
+mod argv {
+    use libc::{c_char, c_int};
+    use std::ptr;
+
+    pub fn get() -> Option<(c_int, *mut *mut c_char)> {
+        Some((0, ptr::null_mut()))
+    }
+}
+
+#[cfg(not(any(all(target_os = "linux", target_env = "gnu"),
+              target_os = "macos", target_os = "ios", windows)))]
+mod argv {
+    use libc::{c_char, c_int};
+
+    pub fn get() -> Option<(c_int, *mut *mut c_char)> {
+        None
+    }
+}
+
+// The state shared by all callers; libuv's title functions are not thread-safe.
+struct Title {
+    initialized: bool,
+    // The command line as it was before `uv_setup_args` took it over.
+    args: Vec<OsString>,
+}
+
+static TITLE: Mutex<Title> = Mutex::new(Title {
+    initialized: false,
+    args: Vec::new(),
+});
+
+fn lock() -> MutexGuard<'static, Title> {
+    TITLE.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn init_locked(title: &mut Title) -> Result<()> {
+    if title.initialized {
+        return Ok(());
+    }
+    let (argc, argv) = argv::get().ok_or(Error::ENOSYS)?;
+    // libuv keeps its copy of the arguments for good.
+    allocator::freeze();
+    unsafe {
+        // The copy libuv returns stays valid, and unchanged, for the life of the process.
+        let copy = if argc > 0 {
+            libuv_sys::uv_setup_args(argc, argv)
+        } else {
+            argv
+        };
+        for i in 0..argc as usize {
+            let arg = CStr::from_ptr(*copy.add(i)).to_bytes().to_vec();
+            title.args.push(bytes_to_path(arg).into_os_string());
+        }
+    }
+    title.initialized = true;
+    Ok(())
+}
+
+/// Hands the command line over to libuv so that the title can be changed.  Does nothing if it
+/// has already been called.  Fails with `ENOSYS` if the command line cannot be found.
+pub fn init() -> Result<()> {
+    init_locked(&mut lock())
+}
+
+/// Returns the command line the process was started with, saved by `init`.
+pub fn args() -> Result<Vec<OsString>> {
+    let mut title = lock();
+    init_locked(&mut title)?;
+    Ok(title.args.clone())
+}
+
+/// Sets the process title.  On Unix it is truncated to the length of the original command
+/// line.
+pub fn set(title: &str) -> Result<()> {
+    let title = CString::new(title).map_err(|_| Error::EINVAL)?;
+    let mut state = lock();
+    init_locked(&mut state)?;
+    unsafe { check(libuv_sys::uv_set_process_title(title.as_ptr())).map(|_| ()) }
+}
+
+/// Returns the process title.
+pub fn get() -> Result<String> {
+    let mut state = lock();
+    init_locked(&mut state)?;
+    let mut buf: Vec<u8> = vec![0; 256];
+    loop {
+        let rc = unsafe {
+            libuv_sys::uv_get_process_title(buf.as_mut_ptr() as *mut c_char, buf.len() as size_t)
+        };
+        if rc == Error::ENOBUFS.code() {
+            let len = buf.len();
+            buf.resize(len * 2, 0);
+            continue;
+        }
+        check(rc)?;
+        let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+        buf.truncate(len);
+        return Ok(String::from_utf8_lossy(&buf).into_owned());
+    }
+}
+
+#[cfg(all(test, any(all(target_os = "linux", target_env = "gnu"), target_os = "macos")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_and_get() {
+        let args = args().unwrap();
+        assert!(!args.is_empty());
+        set("libuv-rs").unwrap();
+        assert_eq!(get().unwrap(), "libuv-rs");
+        assert_eq!(super::args().unwrap(), args);
+        assert_eq!(set("a\0b"), Err(Error::EINVAL));
+    }
+}
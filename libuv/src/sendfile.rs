@@ -0,0 +1,229 @@
+//! Copying a file to a stream in the kernel with `uv_fs_sendfile`.
+//!
+//! libuv runs `sendfile` on its thread pool and hands back whatever the call managed to send, so
+//! a transfer takes as many requests as the socket buffer needs.  libuv streams are
+//! non-blocking, and a request which finds the buffer full fails with `EAGAIN`; the transfer
+//! then waits for the socket to become writable with a `uv_poll_t` before trying again.  Both
+//! work on a duplicate of the stream's descriptor, so the duplicate can be polled alongside the
+//! stream's own watcher and the socket cannot be replaced underneath a request in flight.
+
+use error::{check, Error, Result};
+use handle;
+use libc::{self, c_int, c_void, size_t};
+use libuv_sys::{self, uv_file, uv_fs_t, uv_handle_t, uv_loop_t, uv_os_fd_t, uv_poll_t};
+use std::io;
+use std::ptr;
+use stream::Stream;
+
+// The most requested of one `sendfile` call, which Linux caps just under 2 GiB anyway.
+const CHUNK: u64 = 1 << 30;
+
+struct Transfer {
+    lp: *mut uv_loop_t,
+    out_fd: c_int,
+    in_file: uv_file,
+    offset: u64,
+    remaining: u64,
+    sent: u64,
+    // Allocated the first time the socket buffer fills.
+    poll: *mut uv_poll_t,
+    progress: Box<dyn FnMut(u64)>,
+    cb: Box<dyn FnOnce(Result<u64>)>,
+}
+
+/// Sends `len` bytes of `in_file`, starting at `offset`, to `out` without copying them through
+/// user space.  `progress` receives the total sent so far after each partial transfer, and `cb`
+/// the total once the range has been sent, the end of the file was reached first, or the
+/// transfer failed.  The file's own position is left alone.
+///
+/// The data bypasses `out`'s write queue, so this fails with `EBUSY` while writes are pending,
+/// and the stream should not be written to until `cb` has been called.  The transfer holds its
+/// own reference to the socket, so dropping the stream does not interrupt it.
+pub fn sendfile<S, P, F>(out: &S,
+                         in_file: uv_file,
+                         offset: u64,
+                         len: u64,
+                         progress: P,
+                         cb: F)
+                         -> Result<()>
+    where S: Stream,
+          P: FnMut(u64) + 'static,
+          F: FnOnce(Result<u64>) + 'static
+{
+    if out.write_queue_size() != 0 {
+        return Err(Error::EBUSY);
+    }
+    unsafe {
+        let stream = out.as_raw_stream();
+        let mut fd: uv_os_fd_t = -1;
+        check(libuv_sys::uv_fileno(stream as *const uv_handle_t, &mut fd))?;
+        let out_fd = libc::dup(fd);
+        if out_fd < 0 {
+            return Err(last_error());
+        }
+        let req: *mut uv_fs_t = handle::alloc_req(libuv_sys::UV_FS);
+        let transfer = Box::into_raw(Box::new(Transfer {
+            lp: (*stream).loop_,
+            out_fd,
+            in_file,
+            offset,
+            remaining: len,
+            sent: 0,
+            poll: ptr::null_mut(),
+            progress: Box::new(progress),
+            cb: Box::new(cb),
+        }));
+        (*req).data = transfer as *mut c_void;
+        if let Err(err) = submit(req) {
+            drop(Box::from_raw(transfer));
+            handle::free(req);
+            libc::close(out_fd);
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+fn last_error() -> Error {
+    Error::from_raw(-io::Error::last_os_error().raw_os_error().unwrap_or(libc::EINVAL))
+}
+
+unsafe fn submit(req: *mut uv_fs_t) -> Result<()> {
+    let transfer = &*((*req).data as *const Transfer);
+    let len = transfer.remaining.min(CHUNK) as size_t;
+    check(libuv_sys::uv_fs_sendfile(transfer.lp,
+                                    req,
+                                    transfer.out_fd,
+                                    transfer.in_file,
+                                    transfer.offset as i64,
+                                    len,
+                                    Some(sendfile_cb)))
+        .map(|_| ())
+}
+
+extern "C" fn sendfile_cb(req: *mut uv_fs_t) {
+    unsafe {
+        let result = (*req).result;
+        libuv_sys::uv_fs_req_cleanup(req);
+        let transfer = &mut *((*req).data as *mut Transfer);
+        if result < 0 {
+            let err = Error::from_raw(result as c_int);
+            if err == Error::EAGAIN {
+                if let Err(err) = wait_writable(req) {
+                    finish(req, Err(err));
+                }
+            } else {
+                finish(req, Err(err));
+            }
+            return;
+        }
+        let n = result as u64;
+        transfer.offset += n;
+        transfer.remaining -= n;
+        transfer.sent += n;
+        if n > 0 {
+            (transfer.progress)(transfer.sent);
+        }
+        // A request which sends nothing has reached the end of the file.
+        if transfer.remaining == 0 || n == 0 {
+            let sent = transfer.sent;
+            finish(req, Ok(sent));
+        } else if let Err(err) = submit(req) {
+            finish(req, Err(err));
+        }
+    }
+}
+
+unsafe fn wait_writable(req: *mut uv_fs_t) -> Result<()> {
+    let transfer = &mut *((*req).data as *mut Transfer);
+    if !transfer.poll.is_null() {
+        handle::ensure_open(transfer.poll)?;
+    } else {
+        let poll: *mut uv_poll_t = handle::alloc(libuv_sys::UV_POLL);
+        if let Err(err) = check(libuv_sys::uv_poll_init(transfer.lp, poll, transfer.out_fd)) {
+            handle::free(poll);
+            return Err(err);
+        }
+        (*poll).data = req as *mut c_void;
+        transfer.poll = poll;
+    }
+    check(libuv_sys::uv_poll_start(transfer.poll, libuv_sys::UV_WRITABLE as c_int, poll_cb))
+        .map(|_| ())
+}
+
+extern "C" fn poll_cb(poll: *mut uv_poll_t, status: c_int, _events: c_int) {
+    unsafe {
+        let req = (*poll).data as *mut uv_fs_t;
+        libuv_sys::uv_poll_stop(poll);
+        if let Err(err) = check(status).and_then(|_| submit(req)) {
+            finish(req, Err(err));
+        }
+    }
+}
+
+unsafe fn finish(req: *mut uv_fs_t, result: Result<u64>) {
+    let transfer = Box::from_raw((*req).data as *mut Transfer);
+    handle::free(req);
+    if !transfer.poll.is_null() {
+        // Closing stops the poll at once, so the descriptor can go straight after.
+        (*transfer.poll).data = ptr::null_mut();
+        handle::close::<_, ()>(transfer.poll);
+    }
+    libc::close(transfer.out_fd);
+    (transfer.cb)(result);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use event_loop::{Loop, RunMode};
+    use std::cell::RefCell;
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::os::unix::io::AsRawFd;
+    use std::process;
+    use std::rc::Rc;
+    use std::thread;
+    use tcp::Tcp;
+
+    #[test]
+    fn sends_file_range() {
+        let path = env::temp_dir().join(format!("libuv-sendfile-{}", process::id()));
+        let data: Vec<u8> = (0..4 << 20).map(|i: u32| (i % 251) as u8).collect();
+        File::create(&path).unwrap().write_all(&data).unwrap();
+        let file = File::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let lp = Loop::new().unwrap();
+        let progress = Rc::new(RefCell::new(Vec::new()));
+        let result = Rc::new(RefCell::new(None));
+        let (progress2, result2) = (progress.clone(), result.clone());
+        let fd = file.as_raw_fd();
+        let len = data.len() as u64 - 100;
+        Tcp::connect(&lp, &listener.local_addr().unwrap(), move |conn| {
+                let conn = conn.unwrap();
+                sendfile(&conn,
+                         fd,
+                         10,
+                         len,
+                         move |sent| progress2.borrow_mut().push(sent),
+                         move |sent| *result2.borrow_mut() = Some(sent))
+                    .unwrap();
+            })
+            .unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+        let reader = thread::spawn(move || {
+            let mut buf = Vec::new();
+            peer.read_to_end(&mut buf).unwrap();
+            buf
+        });
+        lp.run(RunMode::Default);
+
+        assert_eq!(*result.borrow(), Some(Ok(len)));
+        assert_eq!(progress.borrow().last(), Some(&len));
+        assert_eq!(reader.join().unwrap(), &data[10..data.len() - 90]);
+    }
+}
@@ -0,0 +1,169 @@
+//! Signal watchers.
+//!
+//! libuv installs a single process-wide handler for each watched signal and delivers it to every
+//! loop with a watcher for it.  On Windows, `SIGINT`, `SIGBREAK`, `SIGHUP` and `SIGWINCH` are
+//! emulated.
+
+use error::{check, Result};
+use event_loop::Loop;
+use handle::{self, CallbackCell};
+use libc::{c_int, c_void};
+use libuv_sys::{self, uv_signal_t};
+
+type SignalCallback = Box<dyn FnMut(i32)>;
+
+/// The signal sent when a terminal's window size changes.  Missing from `libc`; libuv also uses
+/// this number for its emulation on Windows.  Linux numbers it 23 on PA-RISC, which Rust does
+/// not target.
+#[cfg(not(any(target_os = "solaris",
+              target_os = "illumos",
+              all(target_os = "linux",
+                  any(target_arch = "mips",
+                      target_arch = "mips64",
+                      target_arch = "mips32r6",
+                      target_arch = "mips64r6")))))]
+pub const SIGWINCH: i32 = 28;
+/// The signal sent when a terminal's window size changes.
+#[cfg(any(target_os = "solaris",
+          target_os = "illumos",
+          all(target_os = "linux",
+              any(target_arch = "mips",
+                  target_arch = "mips64",
+                  target_arch = "mips32r6",
+                  target_arch = "mips64r6"))))]
+pub const SIGWINCH: i32 = 20;
+
+/// The profiling timer signal.  Missing from `libc`.
+#[cfg(not(any(target_os = "solaris",
+              target_os = "illumos",
+              all(target_os = "linux",
+                  any(target_arch = "mips",
+                      target_arch = "mips64",
+                      target_arch = "mips32r6",
+                      target_arch = "mips64r6")))))]
+pub const SIGPROF: i32 = 27;
+/// The profiling timer signal.
+#[cfg(any(target_os = "solaris",
+          target_os = "illumos",
+          all(target_os = "linux",
+              any(target_arch = "mips",
+                  target_arch = "mips64",
+                  target_arch = "mips32r6",
+                  target_arch = "mips64r6"))))]
+pub const SIGPROF: i32 = 29;
+
+/// Watches for a signal.
+pub struct Signal {
+    raw: *mut uv_signal_t,
+    lp: Loop,
+}
+
+impl Signal {
+    /// Creates a watcher which is not yet started.
+    pub fn new(lp: &Loop) -> Result<Signal> {
+        unsafe {
+            let uv_loop = lp.checked_raw()?;
+            let raw: *mut uv_signal_t = handle::alloc(libuv_sys::UV_SIGNAL);
+            if let Err(err) = check(libuv_sys::uv_signal_init(uv_loop, raw)) {
+                handle::free(raw);
+                return Err(err);
+            }
+            let cb: Box<CallbackCell<SignalCallback>> = Box::new(CallbackCell::new());
+            (*raw).data = Box::into_raw(cb) as *mut c_void;
+            Ok(Signal {
+                raw,
+                lp: lp.clone(),
+            })
+        }
+    }
+
+    /// Returns the underlying `uv_signal_t`.
+    pub fn as_raw(&self) -> *mut uv_signal_t {
+        self.raw
+    }
+
+    /// Returns the loop this watcher belongs to.
+    pub fn get_loop(&self) -> &Loop {
+        &self.lp
+    }
+
+    /// Starts watching for `signum`, replacing any signal and callback set before.  `cb`
+    /// receives the signal number each time it arrives.
+    pub fn start<F>(&self, signum: i32, cb: F) -> Result<()>
+        where F: FnMut(i32) + 'static
+    {
+        unsafe {
+            handle::ensure_open(self.raw)?;
+            let cell = self.callback();
+            cell.set(Box::new(cb));
+            if let Err(err) = check(libuv_sys::uv_signal_start(self.raw, signal_cb, signum)) {
+                cell.clear();
+                return Err(err);
+            }
+            Ok(())
+        }
+    }
+
+    /// Stops watching and drops the callback.
+    pub fn stop(&self) -> Result<()> {
+        unsafe {
+            self.callback().clear();
+            check(libuv_sys::uv_signal_stop(self.raw)).map(|_| ())
+        }
+    }
+
+    /// Returns the signal being watched, or `None` if the watcher is stopped.
+    pub fn signum(&self) -> Option<i32> {
+        match unsafe { (*self.raw).signum } {
+            0 => None,
+            signum => Some(signum),
+        }
+    }
+
+    unsafe fn callback(&self) -> &CallbackCell<SignalCallback> {
+        &*((*self.raw).data as *const CallbackCell<SignalCallback>)
+    }
+}
+
+extern "C" fn signal_cb(raw: *mut uv_signal_t, signum: c_int) {
+    unsafe {
+        let cell = &*((*raw).data as *const CallbackCell<SignalCallback>);
+        cell.with(|cb| cb(signum));
+    }
+}
+
+impl Drop for Signal {
+    fn drop(&mut self) {
+        unsafe { handle::close::<_, CallbackCell<SignalCallback>>(self.raw) }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use event_loop::RunMode;
+    use libc;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    #[test]
+    fn delivers_signal() {
+        let lp = Loop::new().unwrap();
+        let signal = Rc::new(Signal::new(&lp).unwrap());
+        let seen = Rc::new(Cell::new(0));
+        let seen2 = seen.clone();
+        let signal2 = signal.clone();
+        signal.start(libc::SIGHUP, move |signum| {
+                seen2.set(signum);
+                signal2.stop().unwrap();
+            })
+            .unwrap();
+        assert_eq!(signal.signum(), Some(libc::SIGHUP));
+        unsafe {
+            check(libuv_sys::uv_kill(libc::getpid(), libc::SIGHUP)).unwrap();
+        }
+        lp.run(RunMode::Default);
+        assert_eq!(seen.get(), libc::SIGHUP);
+        assert_eq!(signal.signum(), None);
+    }
+}
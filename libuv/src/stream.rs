@@ -0,0 +1,599 @@
+//! Operations shared by every kind of stream: pipes, TCP sockets and TTYs.
+
+use error::{check, Error, Result};
+use event_loop::Loop;
+use handle::{self, CallbackCell};
+use libc::{c_char, c_int, c_uint, c_void, size_t, ssize_t};
+use libuv_sys::{self, uv_buf_t, uv_handle_t, uv_shutdown_t, uv_stream_t, uv_write_t};
+use sealed::Sealed;
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::ptr;
+use std::slice;
+use std::sync::Arc;
+use std::time::Duration;
+use timer::Timer;
+
+type ReadCallback = Box<dyn FnMut(Result<&[u8]>)>;
+type ConnectionCallback = Box<dyn FnMut(Result<()>)>;
+
+/// Owned buffers which `write_vectored` can write without copying.  Their bytes stay where they
+/// are when the buffer is moved, so libuv can point at them until the write completes.
+pub trait WriteBuf: Sealed + 'static {
+    /// Returns the bytes to write.
+    fn as_bytes(&self) -> &[u8];
+}
+
+impl Sealed for Vec<u8> {}
+
+impl WriteBuf for Vec<u8> {
+    fn as_bytes(&self) -> &[u8] {
+        self
+    }
+}
+
+impl Sealed for Box<[u8]> {}
+
+impl WriteBuf for Box<[u8]> {
+    fn as_bytes(&self) -> &[u8] {
+        self
+    }
+}
+
+impl Sealed for Arc<[u8]> {}
+
+impl WriteBuf for Arc<[u8]> {
+    fn as_bytes(&self) -> &[u8] {
+        self
+    }
+}
+
+#[cfg(feature = "bytes")]
+impl Sealed for ::bytes::Bytes {}
+
+#[cfg(feature = "bytes")]
+impl WriteBuf for ::bytes::Bytes {
+    fn as_bytes(&self) -> &[u8] {
+        self
+    }
+}
+
+impl Sealed for Box<dyn WriteBuf> {}
+
+// For mixing kinds of buffer in one write.
+impl WriteBuf for Box<dyn WriteBuf> {
+    fn as_bytes(&self) -> &[u8] {
+        (**self).as_bytes()
+    }
+}
+
+/// Deadlines for a stream's reads and writes, set with `Stream::set_timeouts`.  Every timer
+/// restarts whenever the stream makes progress, so a deadline only passes when the stream has
+/// been stuck for the whole of it.
+#[derive(Clone,Copy,Debug,Default,PartialEq,Eq)]
+pub struct StreamTimeouts {
+    idle: Option<Duration>,
+    read: Option<Duration>,
+    write: Option<Duration>,
+    close_on_idle: bool,
+}
+
+impl StreamTimeouts {
+    /// Returns a configuration with no deadlines.
+    pub fn new() -> StreamTimeouts {
+        StreamTimeouts::default()
+    }
+
+    /// Fails the read in progress with `ETIMEDOUT` once the stream has neither read nor written
+    /// anything for `timeout`, and closes the stream too if `close_on_idle` is set.
+    pub fn idle(&mut self, timeout: Duration) -> &mut StreamTimeouts {
+        self.idle = Some(timeout);
+        self
+    }
+
+    /// Fails the read in progress with `ETIMEDOUT` if no data arrives for `timeout`.  Reading
+    /// stops, as with `read_stop`.
+    pub fn read(&mut self, timeout: Duration) -> &mut StreamTimeouts {
+        self.read = Some(timeout);
+        self
+    }
+
+    /// Fails the pending writes with `ETIMEDOUT` if none of them completes for `timeout`.  libuv
+    /// cannot cancel writes, so this closes the stream.
+    pub fn write(&mut self, timeout: Duration) -> &mut StreamTimeouts {
+        self.write = Some(timeout);
+        self
+    }
+
+    /// Closes the stream when the idle deadline passes.
+    pub fn close_on_idle(&mut self, close: bool) -> &mut StreamTimeouts {
+        self.close_on_idle = close;
+        self
+    }
+}
+
+#[derive(Clone,Copy)]
+enum Which {
+    Idle,
+    Read,
+    Write,
+}
+
+// The timers behind a stream's `StreamTimeouts`.
+struct TimeoutState {
+    config: StreamTimeouts,
+    idle: Option<Timer>,
+    read: Option<Timer>,
+    write: Option<Timer>,
+}
+
+impl TimeoutState {
+    fn timer(&self, which: Which) -> Option<(&Timer, Duration)> {
+        let (timer, timeout) = match which {
+            Which::Idle => (&self.idle, self.config.idle),
+            Which::Read => (&self.read, self.config.read),
+            Which::Write => (&self.write, self.config.write),
+        };
+        match (timer.as_ref(), timeout) {
+            (Some(timer), Some(timeout)) => Some((timer, timeout)),
+            _ => None,
+        }
+    }
+}
+
+fn timer_active(timer: &Timer) -> bool {
+    unsafe { libuv_sys::uv_is_active(timer.as_raw() as *const uv_handle_t) != 0 }
+}
+
+// Whether a timeout has taken over closing the stream.
+#[derive(Clone,Copy,PartialEq,Eq)]
+enum ForcedClose {
+    No,
+    // `uv_close` has been called and its callback has not run yet.
+    Closing,
+    Closed,
+}
+
+/// Per-handle state for streams, stored in the handle's `data` field.
+pub(crate) struct StreamState {
+    read_cb: CallbackCell<ReadCallback>,
+    read_buf: RefCell<Vec<u8>>,
+    reading: Cell<bool>,
+    connection_cb: CallbackCell<ConnectionCallback>,
+    timeouts: RefCell<Option<TimeoutState>>,
+    pending_writes: Cell<usize>,
+    // Reported instead of `ECANCELED` to writes cancelled by a timeout closing the stream.
+    close_error: Cell<Option<Error>>,
+    forced: Cell<ForcedClose>,
+    // Set when the owner drops the stream while a forced close is still in progress.
+    released: Cell<bool>,
+}
+
+impl StreamState {
+    fn new() -> StreamState {
+        StreamState {
+            read_cb: CallbackCell::new(),
+            read_buf: RefCell::new(Vec::new()),
+            reading: Cell::new(false),
+            connection_cb: CallbackCell::new(),
+            timeouts: RefCell::new(None),
+            pending_writes: Cell::new(0),
+            close_error: Cell::new(None),
+            forced: Cell::new(ForcedClose::No),
+            released: Cell::new(false),
+        }
+    }
+
+    // Starts a timer over from its full timeout.
+    unsafe fn arm(&self, stream: *mut uv_stream_t, which: Which) {
+        if let Some(ref timeouts) = *self.timeouts.borrow() {
+            if let Some((timer, timeout)) = timeouts.timer(which) {
+                let stream = stream as usize;
+                let _ = timer.start(timeout, timeout, move || unsafe {
+                    timed_out(stream as *mut uv_stream_t, which)
+                });
+            }
+        }
+    }
+
+    fn disarm(&self, which: Which) {
+        if let Some(ref timeouts) = *self.timeouts.borrow() {
+            if let Some((timer, _)) = timeouts.timer(which) {
+                let _ = timer.stop();
+            }
+        }
+    }
+
+    // Pushes back a running timer's deadline after progress.
+    fn again(&self, which: Which) {
+        if let Some(ref timeouts) = *self.timeouts.borrow() {
+            if let Some((timer, _)) = timeouts.timer(which) {
+                if timer_active(timer) {
+                    let _ = timer.again();
+                }
+            }
+        }
+    }
+
+    /// Returns the state of a stream set up by `attach`.
+    pub(crate) unsafe fn of<'a>(stream: *mut uv_stream_t) -> &'a StreamState {
+        &*((*stream).data as *const StreamState)
+    }
+}
+
+/// Gives a newly initialized stream handle its state.
+pub(crate) unsafe fn attach<H>(stream: *mut H) {
+    let stream = stream as *mut uv_stream_t;
+    (*stream).data = Box::into_raw(Box::new(StreamState::new())) as *mut c_void;
+}
+
+/// Closes a stream handle set up by `attach`.
+pub(crate) unsafe fn close<H>(stream: *mut H) {
+    let state = StreamState::of(stream as *mut uv_stream_t);
+    match state.forced.get() {
+        ForcedClose::No => {
+            // libuv runs due timers before close callbacks, so a deadline passing in this
+            // iteration would otherwise still reach the owner's read callback.
+            state.reading.set(false);
+            state.read_cb.clear();
+            let timeouts = state.timeouts.borrow_mut().take();
+            drop(timeouts);
+            handle::close::<H, StreamState>(stream)
+        }
+        // `forced_close_cb` frees everything once it runs.
+        ForcedClose::Closing => state.released.set(true),
+        ForcedClose::Closed => {
+            drop(Box::from_raw((*(stream as *mut uv_stream_t)).data as *mut StreamState));
+            handle::free(stream);
+        }
+    }
+}
+
+extern "C" fn forced_close_cb(handle: *mut uv_handle_t) {
+    unsafe {
+        let state = StreamState::of(handle as *mut uv_stream_t);
+        if state.released.get() {
+            drop(Box::from_raw((*handle).data as *mut StreamState));
+            handle::free(handle);
+        } else {
+            state.forced.set(ForcedClose::Closed);
+        }
+    }
+}
+
+// Called when one of a stream's deadlines passes.
+unsafe fn timed_out(stream: *mut uv_stream_t, which: Which) {
+    let state = StreamState::of(stream);
+    let close = match which {
+        Which::Idle => state.timeouts.borrow().as_ref().is_some_and(|t| t.config.close_on_idle),
+        Which::Read => false,
+        Which::Write => true,
+    };
+    if close {
+        force_close(stream, Error::ETIMEDOUT);
+        return;
+    }
+    state.disarm(Which::Read);
+    if state.reading.replace(false) {
+        libuv_sys::uv_read_stop(stream);
+        state.read_cb.with(|cb| cb(Err(Error::ETIMEDOUT)));
+    }
+}
+
+// Closes a stream on behalf of a timeout, while its owner still holds it.  The read in
+// progress and the pending writes fail with `err`.
+unsafe fn force_close(stream: *mut uv_stream_t, err: Error) {
+    let handle = stream as *mut uv_handle_t;
+    if libuv_sys::uv_is_closing(handle) != 0 {
+        return;
+    }
+    let state = StreamState::of(stream);
+    state.close_error.set(Some(err));
+    if state.reading.replace(false) {
+        libuv_sys::uv_read_stop(stream);
+        state.read_cb.with(|cb| cb(Err(err)));
+        // The callback may have dropped the stream, which closes it the usual way.
+        if libuv_sys::uv_is_closing(handle) != 0 {
+            return;
+        }
+    }
+    let timeouts = state.timeouts.borrow_mut().take();
+    drop(timeouts);
+    state.forced.set(ForcedClose::Closing);
+    libuv_sys::uv_close(handle, forced_close_cb);
+}
+
+/// Sets the callback for incoming connections and starts listening.
+pub(crate) unsafe fn listen(stream: *mut uv_stream_t,
+                            backlog: c_int,
+                            cb: ConnectionCallback)
+                            -> Result<()> {
+    handle::ensure_open(stream)?;
+    let state = StreamState::of(stream);
+    state.connection_cb.set(cb);
+    if let Err(err) = check(libuv_sys::uv_listen(stream, backlog, connection_cb)) {
+        state.connection_cb.clear();
+        return Err(err);
+    }
+    Ok(())
+}
+
+extern "C" fn connection_cb(server: *mut uv_stream_t, status: c_int) {
+    unsafe {
+        StreamState::of(server).connection_cb.with(|cb| cb(check(status).map(|_| ())));
+    }
+}
+
+extern "C" fn alloc_cb(handle: *mut uv_handle_t, suggested: size_t, buf: *mut uv_buf_t) {
+    unsafe {
+        let state = StreamState::of(handle as *mut uv_stream_t);
+        let mut read_buf = state.read_buf.borrow_mut();
+        if read_buf.len() < suggested as usize {
+            read_buf.resize(suggested as usize, 0);
+        }
+        *buf = libuv_sys::uv_buf_init(read_buf.as_mut_ptr() as *mut c_char,
+                                      read_buf.len() as c_uint);
+    }
+}
+
+extern "C" fn read_cb(stream: *mut uv_stream_t, nread: ssize_t, buf: *const uv_buf_t) {
+    unsafe {
+        // Zero means the read would have blocked; it is not end of file.
+        if nread == 0 {
+            return;
+        }
+        let state = StreamState::of(stream);
+        let result = if nread < 0 {
+            // Nothing more will arrive, so there is nothing left for the read deadline to time.
+            state.reading.set(false);
+            state.disarm(Which::Read);
+            Err(Error::from_raw(nread as c_int))
+        } else {
+            state.again(Which::Read);
+            state.again(Which::Idle);
+            Ok(slice::from_raw_parts((*buf).base as *const u8, nread as usize))
+        };
+        state.read_cb.with(|cb| cb(result));
+    }
+}
+
+struct WriteReq {
+    // Referenced by libuv until the write completes, as is any handle being sent.
+    _data: Box<dyn Any>,
+    _send_handle: Option<Box<dyn Any>>,
+    cb: Box<dyn FnOnce(Result<()>)>,
+}
+
+/// Queues a write of the buffers in `data`, in order, optionally sending a handle over an IPC
+/// pipe.  The owner of the handle is kept alive until the write completes.
+pub(crate) unsafe fn queue_write<B: WriteBuf>(stream: *mut uv_stream_t,
+                                             data: Vec<B>,
+                                             send_handle: Option<(*mut uv_stream_t,
+                                                                  Box<dyn Any>)>,
+                                             cb: Box<dyn FnOnce(Result<()>)>)
+                                             -> Result<()> {
+    handle::ensure_open(stream)?;
+    // libuv copies the buffer descriptors, though not the data, before returning.
+    let bufs: Vec<uv_buf_t> = data.iter()
+        .map(|buf| {
+            let buf = buf.as_bytes();
+            libuv_sys::uv_buf_init(buf.as_ptr() as *mut c_char, buf.len() as c_uint)
+        })
+        .collect();
+    let nbufs = bufs.len() as c_uint;
+    let req: *mut uv_write_t = handle::alloc_req(libuv_sys::UV_WRITE);
+    let (raw_handle, owner) = match send_handle {
+        Some((raw_handle, owner)) => (raw_handle, Some(owner)),
+        None => (ptr::null_mut(), None),
+    };
+    let write = Box::into_raw(Box::new(WriteReq {
+        _data: Box::new(data),
+        _send_handle: owner,
+        cb,
+    }));
+    (*req).data = write as *mut c_void;
+    let rc = if raw_handle.is_null() {
+        libuv_sys::uv_write(req, stream, bufs.as_ptr(), nbufs, write_cb)
+    } else {
+        libuv_sys::uv_write2(req, stream, bufs.as_ptr(), nbufs, raw_handle, write_cb)
+    };
+    if let Err(err) = check(rc) {
+        drop(Box::from_raw(write));
+        handle::free(req);
+        return Err(err);
+    }
+    let state = StreamState::of(stream);
+    state.pending_writes.set(state.pending_writes.get() + 1);
+    if state.pending_writes.get() == 1 {
+        state.arm(stream, Which::Write);
+    }
+    Ok(())
+}
+
+extern "C" fn write_cb(req: *mut uv_write_t, status: c_int) {
+    unsafe {
+        let write = Box::from_raw((*req).data as *mut WriteReq);
+        let stream = (*req).handle;
+        handle::free(req);
+        let state = StreamState::of(stream);
+        state.pending_writes.set(state.pending_writes.get() - 1);
+        let mut result = check(status).map(|_| ());
+        if result == Err(Error::ECANCELED) {
+            if let Some(err) = state.close_error.get() {
+                result = Err(err);
+            }
+        } else if state.pending_writes.get() == 0 {
+            state.disarm(Which::Write);
+            state.again(Which::Idle);
+        } else {
+            state.again(Which::Write);
+            state.again(Which::Idle);
+        }
+        (write.cb)(result);
+    }
+}
+
+extern "C" fn shutdown_cb(req: *mut uv_shutdown_t, status: c_int) {
+    unsafe {
+        let cb = Box::from_raw((*req).data as *mut Box<dyn FnOnce(Result<()>)>);
+        handle::free(req);
+        cb(check(status).map(|_| ()));
+    }
+}
+
+/// Operations common to every kind of stream.  The methods taking callbacks are only available on
+/// concrete types, so that `&dyn Stream` can still refer to any open stream.
+pub trait Stream: Sealed {
+    /// Returns the underlying `uv_stream_t`.
+    fn as_raw_stream(&self) -> *mut uv_stream_t;
+
+    /// Starts reading.  `cb` receives each chunk of data as it arrives, `Err(Error::EOF)` once the
+    /// peer has shut down its side, or any other read error.  The slice is only valid for the
+    /// duration of the call.
+    fn read_start<F>(&self, cb: F) -> Result<()>
+        where F: FnMut(Result<&[u8]>) + 'static,
+              Self: Sized
+    {
+        unsafe {
+            let stream = self.as_raw_stream();
+            handle::ensure_open(stream)?;
+            let state = StreamState::of(stream);
+            state.read_cb.set(Box::new(cb));
+            if let Err(err) = check(libuv_sys::uv_read_start(stream, alloc_cb, read_cb)) {
+                state.read_cb.clear();
+                return Err(err);
+            }
+            if !state.reading.replace(true) {
+                state.arm(stream, Which::Read);
+            }
+            Ok(())
+        }
+    }
+
+    /// Stops reading and drops the read callback.
+    fn read_stop(&self) -> Result<()> {
+        unsafe {
+            let stream = self.as_raw_stream();
+            let state = StreamState::of(stream);
+            state.read_cb.clear();
+            state.reading.set(false);
+            state.disarm(Which::Read);
+            check(libuv_sys::uv_read_stop(stream)).map(|_| ())
+        }
+    }
+
+    /// Queues `data` to be written.  Writes complete in order; `cb` is called once the data has
+    /// been handed to the operating system, or with `ECANCELED` if the stream is closed first.
+    fn write<F>(&self, data: Vec<u8>, cb: F) -> Result<()>
+        where F: FnOnce(Result<()>) + 'static,
+              Self: Sized
+    {
+        unsafe { queue_write(self.as_raw_stream(), vec![data], None, Box::new(cb)) }
+    }
+
+    /// Queues the buffers in `bufs` to be written in order as a single write, without copying
+    /// them.  Each buffer is kept alive until `cb` is called, so a shared buffer such as an
+    /// `Arc<[u8]>` can be written to many streams at once.  Fails with `EINVAL` if `bufs` is
+    /// empty.
+    fn write_vectored<B, F>(&self, bufs: Vec<B>, cb: F) -> Result<()>
+        where B: WriteBuf,
+              F: FnOnce(Result<()>) + 'static,
+              Self: Sized
+    {
+        if bufs.is_empty() {
+            return Err(Error::EINVAL);
+        }
+        unsafe { queue_write(self.as_raw_stream(), bufs, None, Box::new(cb)) }
+    }
+
+    /// Writes as much of `data` as possible without blocking or queueing, returning the number
+    /// of bytes written.  Fails with `EAGAIN` if nothing could be written immediately.
+    fn try_write(&self, data: &[u8]) -> Result<usize> {
+        unsafe {
+            handle::ensure_open(self.as_raw_stream())?;
+            let buf = libuv_sys::uv_buf_init(data.as_ptr() as *mut c_char, data.len() as c_uint);
+            check(libuv_sys::uv_try_write(self.as_raw_stream(), &buf, 1)).map(|n| n as usize)
+        }
+    }
+
+    /// Returns the number of bytes queued by `write` which have not been written yet.
+    fn write_queue_size(&self) -> usize {
+        unsafe { (*self.as_raw_stream()).write_queue_size as usize }
+    }
+
+    /// Shuts down the outgoing side of the stream once all pending writes have completed.
+    fn shutdown<F>(&self, cb: F) -> Result<()>
+        where F: FnOnce(Result<()>) + 'static,
+              Self: Sized
+    {
+        unsafe {
+            handle::ensure_open(self.as_raw_stream())?;
+            let req: *mut uv_shutdown_t = handle::alloc_req(libuv_sys::UV_SHUTDOWN);
+            let cb: *mut Box<dyn FnOnce(Result<()>)> = Box::into_raw(Box::new(Box::new(cb)));
+            (*req).data = cb as *mut c_void;
+            let rc = libuv_sys::uv_shutdown(req, self.as_raw_stream(), shutdown_cb);
+            if let Err(err) = check(rc) {
+                drop(Box::from_raw(cb));
+                handle::free(req);
+                return Err(err);
+            }
+            Ok(())
+        }
+    }
+
+    /// Replaces the stream's deadlines; `StreamTimeouts::new()` removes them all.  Timers start
+    /// from now for reads and writes already in progress.
+    fn set_timeouts(&self, timeouts: &StreamTimeouts) -> Result<()> {
+        unsafe {
+            let stream = self.as_raw_stream();
+            handle::ensure_open(stream)?;
+            let state = StreamState::of(stream);
+            let lp = Loop::from_raw((*stream).loop_);
+            let timer = |timeout: Option<Duration>| -> Result<Option<Timer>> {
+                match timeout {
+                    Some(_) => Timer::new(&lp).map(Some),
+                    None => Ok(None),
+                }
+            };
+            let new = TimeoutState {
+                config: *timeouts,
+                idle: timer(timeouts.idle)?,
+                read: timer(timeouts.read)?,
+                write: timer(timeouts.write)?,
+            };
+            // The idle timer repeats for as long as the stream is open, and should not keep the
+            // loop alive for a stream that is doing nothing.
+            if let Some(ref idle) = new.idle {
+                libuv_sys::uv_unref(idle.as_raw() as *mut uv_handle_t);
+            }
+            let old = state.timeouts.borrow_mut().replace(new);
+            drop(old);
+            state.arm(stream, Which::Idle);
+            if state.reading.get() {
+                state.arm(stream, Which::Read);
+            }
+            if state.pending_writes.get() > 0 {
+                state.arm(stream, Which::Write);
+            }
+            Ok(())
+        }
+    }
+
+    /// Returns true if the stream can be read from.
+    fn is_readable(&self) -> bool {
+        unsafe { libuv_sys::uv_is_readable(self.as_raw_stream()) != 0 }
+    }
+
+    /// Returns true if the stream can be written to.
+    fn is_writable(&self) -> bool {
+        unsafe { libuv_sys::uv_is_writable(self.as_raw_stream()) != 0 }
+    }
+
+    /// Switches the stream between blocking and non-blocking writes.
+    fn set_blocking(&self, blocking: bool) -> Result<()> {
+        unsafe {
+            check(libuv_sys::uv_stream_set_blocking(self.as_raw_stream(), blocking as c_int))
+                .map(|_| ())
+        }
+    }
+}
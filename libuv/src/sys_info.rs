@@ -0,0 +1,299 @@
+//! Information about the host and the current process.
+
+use addr;
+use allocator;
+use error::{check, Result};
+use libc::{c_char, c_int, size_t, sockaddr};
+use libuv_sys::{self, uv_cpu_info_t, uv_cpu_times_t, uv_interface_address_t, uv_rusage_t,
+                uv_timeval_t};
+use std::ffi::CStr;
+use std::mem;
+use std::net::IpAddr;
+use std::ptr;
+use std::slice;
+use std::time::Duration;
+
+/// Time a CPU has spent in each mode since boot, in milliseconds.
+#[derive(Clone,Copy,Debug,Default,PartialEq,Eq,Hash)]
+pub struct CpuTimes {
+    /// Running user code.
+    pub user: u64,
+    /// Running user code at lowered priority.
+    pub nice: u64,
+    /// Running kernel code.
+    pub sys: u64,
+    /// Idle.
+    pub idle: u64,
+    /// Servicing interrupts.
+    pub irq: u64,
+}
+
+impl CpuTimes {
+    /// Returns the time spent in all modes.
+    pub fn total(&self) -> u64 {
+        self.user + self.nice + self.sys + self.idle + self.irq
+    }
+}
+
+impl From<uv_cpu_times_t> for CpuTimes {
+    fn from(times: uv_cpu_times_t) -> CpuTimes {
+        CpuTimes {
+            user: times.user,
+            nice: times.nice,
+            sys: times.sys,
+            idle: times.idle,
+            irq: times.irq,
+        }
+    }
+}
+
+/// A logical CPU.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct CpuInfo {
+    /// The processor's model name.
+    pub model: String,
+    /// The clock speed in MHz.
+    pub speed: i32,
+    /// Time spent in each mode.
+    pub times: CpuTimes,
+}
+
+/// An address assigned to a network interface.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct InterfaceAddress {
+    /// The interface's name.
+    pub name: String,
+    /// The interface's hardware address.
+    pub phys_addr: [u8; 6],
+    /// Whether this is a loopback interface.
+    pub is_internal: bool,
+    /// The address.
+    pub address: IpAddr,
+    /// The address's netmask.
+    pub netmask: IpAddr,
+}
+
+/// Resource usage of the current process.
+#[derive(Clone,Copy,Debug,Default,PartialEq,Eq,Hash)]
+pub struct ResourceUsage {
+    /// CPU time spent running user code.
+    pub user_time: Duration,
+    /// CPU time spent running kernel code.
+    pub system_time: Duration,
+    /// Maximum resident set size, in kilobytes.
+    pub max_rss: u64,
+    /// Integral shared memory size.
+    pub ixrss: u64,
+    /// Integral unshared data size.
+    pub idrss: u64,
+    /// Integral unshared stack size.
+    pub isrss: u64,
+    /// Page faults serviced without I/O.
+    pub minor_faults: u64,
+    /// Page faults which required I/O.
+    pub major_faults: u64,
+    /// Times swapped out.
+    pub swaps: u64,
+    /// Block input operations.
+    pub in_blocks: u64,
+    /// Block output operations.
+    pub out_blocks: u64,
+    /// IPC messages sent.
+    pub messages_sent: u64,
+    /// IPC messages received.
+    pub messages_received: u64,
+    /// Signals received.
+    pub signals: u64,
+    /// Voluntary context switches.
+    pub voluntary_switches: u64,
+    /// Involuntary context switches.
+    pub involuntary_switches: u64,
+}
+
+fn duration(tv: uv_timeval_t) -> Duration {
+    Duration::new(tv.tv_sec as u64, tv.tv_usec as u32 * 1000)
+}
+
+unsafe fn string(s: *const c_char) -> String {
+    if s.is_null() {
+        return String::new();
+    }
+    CStr::from_ptr(s).to_string_lossy().into_owned()
+}
+
+// Frees the array returned by `uv_cpu_info` even if converting it panics.
+struct CpuInfoArray(*mut uv_cpu_info_t, c_int);
+
+impl Drop for CpuInfoArray {
+    fn drop(&mut self) {
+        unsafe { libuv_sys::uv_free_cpu_info(self.0, self.1) }
+    }
+}
+
+// Likewise for `uv_interface_addresses`.
+struct InterfaceAddressArray(*mut uv_interface_address_t, c_int);
+
+impl Drop for InterfaceAddressArray {
+    fn drop(&mut self) {
+        unsafe { libuv_sys::uv_free_interface_addresses(self.0, self.1) }
+    }
+}
+
+/// Returns information about each logical CPU.
+pub fn cpu_info() -> Result<Vec<CpuInfo>> {
+    allocator::freeze();
+    unsafe {
+        let mut infos = ptr::null_mut();
+        let mut count = 0;
+        check(libuv_sys::uv_cpu_info(&mut infos, &mut count))?;
+        let array = CpuInfoArray(infos, count);
+        Ok(slice::from_raw_parts(array.0, array.1 as usize)
+            .iter()
+            .map(|info| {
+                CpuInfo {
+                    model: string(info.model),
+                    speed: info.speed,
+                    times: info.cpu_times.into(),
+                }
+            })
+            .collect())
+    }
+}
+
+/// Returns the addresses of the host's network interfaces.  Interfaces with several addresses
+/// appear once for each.  Addresses of families other than IPv4 and IPv6 are left out.
+pub fn interface_addresses() -> Result<Vec<InterfaceAddress>> {
+    allocator::freeze();
+    unsafe {
+        let mut addresses = ptr::null_mut();
+        let mut count = 0;
+        check(libuv_sys::uv_interface_addresses(&mut addresses, &mut count))?;
+        let array = InterfaceAddressArray(addresses, count);
+        let mut result = Vec::with_capacity(count as usize);
+        for entry in slice::from_raw_parts(array.0, array.1 as usize) {
+            // An entry of an unexpected family is skipped rather than failing the whole list.
+            let address = match addr::from_raw(&entry.u_address as *const _ as *const sockaddr) {
+                Ok(address) => address,
+                Err(_) => continue,
+            };
+            let netmask = match addr::from_raw(&entry.u_netmask as *const _ as *const sockaddr) {
+                Ok(netmask) => netmask,
+                Err(_) => continue,
+            };
+            let mut phys_addr = [0; 6];
+            for (dst, src) in phys_addr.iter_mut().zip(entry.phys_addr.iter()) {
+                *dst = *src as u8;
+            }
+            result.push(InterfaceAddress {
+                name: string(entry.name),
+                phys_addr,
+                is_internal: entry.is_internal != 0,
+                address: address.ip(),
+                netmask: netmask.ip(),
+            });
+        }
+        Ok(result)
+    }
+}
+
+/// Returns the 1, 5 and 15 minute load averages.  Always zero on Windows.
+pub fn loadavg() -> [f64; 3] {
+    let mut avg = [0.0; 3];
+    unsafe { libuv_sys::uv_loadavg(&mut avg) }
+    avg
+}
+
+/// Returns the time since the host booted.
+pub fn uptime() -> Result<Duration> {
+    let mut uptime = 0.0;
+    unsafe {
+        check(libuv_sys::uv_uptime(&mut uptime))?;
+    }
+    Ok(Duration::from_secs_f64(uptime))
+}
+
+/// Returns the resident set size of the current process, in bytes.
+pub fn resident_set_memory() -> Result<usize> {
+    let mut rss: size_t = 0;
+    unsafe {
+        check(libuv_sys::uv_resident_set_memory(&mut rss))?;
+    }
+    Ok(rss as usize)
+}
+
+/// Returns the resource usage of the current process.  Only the times and `max_rss` are
+/// available on Windows.
+pub fn getrusage() -> Result<ResourceUsage> {
+    unsafe {
+        let mut usage: uv_rusage_t = mem::zeroed();
+        check(libuv_sys::uv_getrusage(&mut usage))?;
+        Ok(ResourceUsage {
+            user_time: duration(usage.ru_utime),
+            system_time: duration(usage.ru_stime),
+            max_rss: usage.ru_maxrss,
+            ixrss: usage.ru_ixrss,
+            idrss: usage.ru_idrss,
+            isrss: usage.ru_isrss,
+            minor_faults: usage.ru_minflt,
+            major_faults: usage.ru_majflt,
+            swaps: usage.ru_nswap,
+            in_blocks: usage.ru_inblock,
+            out_blocks: usage.ru_oublock,
+            messages_sent: usage.ru_msgsnd,
+            messages_received: usage.ru_msgrcv,
+            signals: usage.ru_nsignals,
+            voluntary_switches: usage.ru_nvcsw,
+            involuntary_switches: usage.ru_nivcsw,
+        })
+    }
+}
+
+/// Returns the amount of free memory, in bytes.
+pub fn free_memory() -> u64 {
+    unsafe { libuv_sys::uv_get_free_memory() }
+}
+
+/// Returns the total amount of memory, in bytes.
+pub fn total_memory() -> u64 {
+    unsafe { libuv_sys::uv_get_total_memory() }
+}
+
+/// Returns the current high-resolution time in nanoseconds.  It is relative to an arbitrary time
+/// in the past, so only differences between readings are meaningful.
+pub fn hrtime() -> u64 {
+    unsafe { libuv_sys::uv_hrtime() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cpus() {
+        let cpus = cpu_info().unwrap();
+        assert!(!cpus.is_empty());
+        assert!(cpus.iter().all(|cpu| cpu.times.total() > 0));
+    }
+
+    #[test]
+    fn loopback_is_internal() {
+        let addresses = interface_addresses().unwrap();
+        assert!(addresses.iter().any(|a| a.is_internal && a.address.is_loopback()));
+    }
+
+    #[test]
+    fn memory_and_usage() {
+        assert!(total_memory() >= free_memory());
+        assert!(resident_set_memory().unwrap() > 0);
+        assert!(getrusage().unwrap().max_rss > 0);
+        assert!(uptime().unwrap() > Duration::from_secs(0));
+        assert!(loadavg().iter().all(|&avg| avg >= 0.0));
+    }
+
+    #[test]
+    fn hrtime_is_monotonic() {
+        let a = hrtime();
+        let b = hrtime();
+        assert!(b >= a);
+    }
+}
@@ -0,0 +1,315 @@
+//! A single-threaded executor for futures which run on a `Loop`.
+//!
+//! Spawned futures stay on the loop thread, wrapped in `ThreadBound` capabilities, while their
+//! wakers may travel anywhere.  Waking a task pushes it onto a shared ready queue and signals a
+//! `uv_async_t`; the async callback starts a `uv_idle_t` which polls the queued tasks once per
+//! loop iteration until the queue is empty.
+
+use error::{check, Result};
+use event_loop::Loop;
+use handle;
+use libuv_sys::{self, uv_async_t, uv_idle_t, uv_handle_t};
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::ptr;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::task::{Context, Wake, Waker};
+use thread_bound::ThreadBound;
+
+type LocalFuture = Pin<Box<dyn Future<Output = ()>>>;
+
+struct AsyncPtr(*mut uv_async_t);
+
+// uv_async_send is the one libuv function which may be called from any thread.
+unsafe impl Send for AsyncPtr {}
+
+struct Shared {
+    ready: Mutex<VecDeque<Arc<Task>>>,
+    // Cleared before the async handle is closed, so that wakers which outlive the loop never
+    // signal a closed handle or queue tasks that will never be polled.
+    wakeup: Mutex<Option<AsyncPtr>>,
+    live: AtomicUsize,
+}
+
+impl Shared {
+    fn signal(&self) {
+        if let Some(ref async_) = *self.wakeup.lock().unwrap() {
+            unsafe {
+                libuv_sys::uv_async_send(async_.0);
+            }
+        }
+    }
+}
+
+struct Task {
+    future: ThreadBound<RefCell<Option<LocalFuture>>>,
+    queued: AtomicBool,
+    done: AtomicBool,
+    shared: Arc<Shared>,
+}
+
+impl Task {
+    fn poll(self: &Arc<Self>) {
+        self.queued.store(false, Ordering::SeqCst);
+        let waker = Waker::from(self.clone());
+        let mut cx = Context::from_waker(&waker);
+        let finished = self.future.with(|slot| {
+            let mut slot = slot.borrow_mut();
+            let finished = match *slot {
+                Some(ref mut future) => future.as_mut().poll(&mut cx).is_ready(),
+                None => false,
+            };
+            if finished {
+                *slot = None;
+            }
+            finished
+        });
+        if finished {
+            self.done.store(true, Ordering::SeqCst);
+            self.shared.live.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+impl Wake for Task {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        if self.done.load(Ordering::SeqCst) || self.queued.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let wakeup = self.shared.wakeup.lock().unwrap();
+        if let Some(ref async_) = *wakeup {
+            self.shared.ready.lock().unwrap().push_back(self.clone());
+            unsafe {
+                libuv_sys::uv_async_send(async_.0);
+            }
+        }
+    }
+}
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        // Every waker is gone, so a task which never finished can no longer make progress and
+        // should not keep the loop alive.
+        if !self.done.load(Ordering::SeqCst) {
+            self.shared.live.fetch_sub(1, Ordering::SeqCst);
+            self.shared.signal();
+        }
+    }
+}
+
+struct Poller {
+    shared: Arc<Shared>,
+    async_: *mut uv_async_t,
+    idle: *mut uv_idle_t,
+    closed: Cell<bool>,
+}
+
+impl Poller {
+    // The async handle is only referenced while tasks are pending, so an idle executor does not
+    // prevent the loop from exiting.
+    unsafe fn sync_ref(&self) {
+        let handle = self.async_ as *mut uv_handle_t;
+        if self.shared.live.load(Ordering::SeqCst) == 0 {
+            libuv_sys::uv_unref(handle);
+        } else {
+            libuv_sys::uv_ref(handle);
+        }
+    }
+
+    unsafe fn poll_ready(&self) {
+        // Only tasks queued before this turn are polled, so a task which keeps waking itself
+        // cannot starve I/O.
+        let batch = self.shared.ready.lock().unwrap().len();
+        for _ in 0..batch {
+            let task = match self.shared.ready.lock().unwrap().pop_front() {
+                Some(task) => task,
+                None => break,
+            };
+            task.poll();
+            if self.closed.get() {
+                return;
+            }
+        }
+        if self.shared.ready.lock().unwrap().is_empty() {
+            libuv_sys::uv_idle_stop(self.idle);
+        }
+        self.sync_ref();
+    }
+}
+
+extern "C" fn async_cb(async_: *mut uv_async_t) {
+    unsafe {
+        let poller = (*async_).data as *const Poller;
+        if !poller.is_null() {
+            libuv_sys::uv_idle_start((*poller).idle, idle_cb);
+            (*poller).sync_ref();
+        }
+    }
+}
+
+extern "C" fn idle_cb(idle: *mut uv_idle_t) {
+    unsafe {
+        let poller = (*idle).data as *const Poller;
+        if !poller.is_null() {
+            (*poller).poll_ready();
+        }
+    }
+}
+
+/// The per-loop task queue behind `Loop::spawn_local`.
+#[derive(Clone)]
+pub struct Executor {
+    poller: Rc<Poller>,
+}
+
+impl Executor {
+    pub fn new(lp: &Loop) -> Result<Executor> {
+        unsafe {
+            let uv_loop = lp.checked_raw()?;
+            let async_: *mut uv_async_t = handle::alloc(libuv_sys::UV_ASYNC);
+            if let Err(err) = check(libuv_sys::uv_async_init(uv_loop, async_, async_cb)) {
+                handle::free(async_);
+                return Err(err);
+            }
+            let idle: *mut uv_idle_t = handle::alloc(libuv_sys::UV_IDLE);
+            if let Err(err) = check(libuv_sys::uv_idle_init(uv_loop, idle)) {
+                handle::free(idle);
+                handle::close::<_, ()>(async_);
+                return Err(err);
+            }
+
+            let poller = Rc::new(Poller {
+                shared: Arc::new(Shared {
+                    ready: Mutex::new(VecDeque::new()),
+                    wakeup: Mutex::new(Some(AsyncPtr(async_))),
+                    live: AtomicUsize::new(0),
+                }),
+                async_,
+                idle,
+                closed: Cell::new(false),
+            });
+            (*async_).data = &*poller as *const Poller as *mut _;
+            (*idle).data = &*poller as *const Poller as *mut _;
+            poller.sync_ref();
+            Ok(Executor { poller })
+        }
+    }
+
+    pub fn spawn(&self, future: LocalFuture) {
+        let shared = &self.poller.shared;
+        let task = Arc::new(Task {
+            future: ThreadBound::new(RefCell::new(Some(future))),
+            queued: AtomicBool::new(true),
+            done: AtomicBool::new(false),
+            shared: shared.clone(),
+        });
+        shared.live.fetch_add(1, Ordering::SeqCst);
+        shared.ready.lock().unwrap().push_back(task);
+        unsafe {
+            libuv_sys::uv_idle_start(self.poller.idle, idle_cb);
+            self.poller.sync_ref();
+        }
+    }
+
+    /// Closes the executor's handles and drops every queued task.  Tasks held only by wakers
+    /// are dropped along with their last waker.
+    pub fn shutdown(&self) {
+        let poller = &self.poller;
+        if poller.closed.replace(true) {
+            return;
+        }
+        *poller.shared.wakeup.lock().unwrap() = None;
+        unsafe {
+            (*poller.async_).data = ptr::null_mut();
+            (*poller.idle).data = ptr::null_mut();
+            handle::close::<_, ()>(poller.async_);
+            handle::close::<_, ()>(poller.idle);
+        }
+        let queued: VecDeque<_> = poller.shared.ready.lock().unwrap().drain(..).collect();
+        drop(queued);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use event_loop::{Loop, RunMode};
+    use std::cell::Cell;
+    use std::future::poll_fn;
+    use std::rc::Rc;
+    use std::task::{Context, Poll};
+    use std::thread;
+    use std::time::Duration;
+
+    // Spawns a task which returns `Pending` on its first poll, after passing its context to
+    // `first`, and sets the returned flag when polled again.
+    fn spawn_yield_once<F>(lp: &Loop, mut first: F) -> Rc<Cell<bool>>
+        where F: FnMut(&mut Context) + 'static
+    {
+        let done = Rc::new(Cell::new(false));
+        let done2 = done.clone();
+        let mut yielded = false;
+        lp.spawn_local(poll_fn(move |cx| {
+                if yielded {
+                    done2.set(true);
+                    return Poll::Ready(());
+                }
+                yielded = true;
+                first(cx);
+                Poll::Pending
+            }))
+            .unwrap();
+        done
+    }
+
+    #[test]
+    fn runs_non_send_future() {
+        let lp = Loop::new().unwrap();
+        let ran = Rc::new(Cell::new(false));
+        let ran2 = ran.clone();
+        lp.spawn_local(poll_fn(move |_| {
+                ran2.set(true);
+                Poll::Ready(())
+            }))
+            .unwrap();
+        lp.run(RunMode::Default);
+        assert!(ran.get());
+    }
+
+    #[test]
+    fn self_wake_is_repolled() {
+        let lp = Loop::new().unwrap();
+        let done = spawn_yield_once(&lp, |cx| cx.waker().wake_by_ref());
+        lp.run(RunMode::Default);
+        assert!(done.get());
+    }
+
+    #[test]
+    fn woken_from_other_thread() {
+        let lp = Loop::new().unwrap();
+        let done = spawn_yield_once(&lp, |cx| {
+            let waker = cx.waker().clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(10));
+                waker.wake();
+            });
+        });
+        lp.run(RunMode::Default);
+        assert!(done.get());
+    }
+
+    #[test]
+    fn abandoned_task_releases_loop() {
+        let lp = Loop::new().unwrap();
+        let done = spawn_yield_once(&lp, |_| ());
+        assert!(!lp.run(RunMode::Default));
+        assert!(!done.get());
+    }
+}
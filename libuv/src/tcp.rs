@@ -0,0 +1,379 @@
+//! TCP sockets.
+
+use addr::{self, SockAddr};
+use error::{check, Result};
+use event_loop::Loop;
+use handle;
+use libc::{c_int, c_uint, c_void};
+use libuv_sys::{self, uv_connect_t, uv_os_sock_t, uv_stream_t, uv_tcp_t};
+use sealed::Sealed;
+use std::net::SocketAddr;
+use stream::{self, Stream};
+
+/// A TCP socket.  As with `uv_tcp_t`, the same type is used for listening and connected sockets,
+/// since a socket received from another process may be either.
+pub struct Tcp {
+    raw: *mut uv_tcp_t,
+    lp: Loop,
+}
+
+struct ConnectReq {
+    tcp: Tcp,
+    cb: Box<dyn FnOnce(Result<Tcp>)>,
+}
+
+extern "C" fn connect_cb(req: *mut uv_connect_t, status: c_int) {
+    unsafe {
+        let ConnectReq { tcp, cb } = *Box::from_raw((*req).data as *mut ConnectReq);
+        handle::free(req);
+        cb(check(status).map(|_| tcp));
+    }
+}
+
+impl Tcp {
+    /// Connects to `addr`.  The connection is made asynchronously and `cb` receives either the
+    /// connected socket or the reason the connection failed.
+    pub fn connect<F>(lp: &Loop, addr: &SocketAddr, cb: F) -> Result<()>
+        where F: FnOnce(Result<Tcp>) + 'static
+    {
+        let addr = SockAddr::from(*addr);
+        let tcp = Tcp::new(lp)?;
+        unsafe {
+            let raw = tcp.raw;
+            let req: *mut uv_connect_t = handle::alloc_req(libuv_sys::UV_CONNECT);
+            let connect = Box::into_raw(Box::new(ConnectReq {
+                tcp,
+                cb: Box::new(cb),
+            }));
+            (*req).data = connect as *mut c_void;
+            let rc = libuv_sys::uv_tcp_connect(req, raw, addr.as_ptr(), connect_cb);
+            if let Err(err) = check(rc) {
+                drop(Box::from_raw(connect));
+                handle::free(req);
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates a socket bound to `addr`, ready to `listen`.  Binding to port 0 picks a free
+    /// port; see `sockname`.
+    pub fn bind(lp: &Loop, addr: &SocketAddr) -> Result<Tcp> {
+        let addr = SockAddr::from(*addr);
+        let tcp = Tcp::new(lp)?;
+        unsafe {
+            check(libuv_sys::uv_tcp_bind(tcp.raw, addr.as_ptr(), 0))?;
+        }
+        Ok(tcp)
+    }
+
+    /// Creates a socket which is not yet bound, listening or connected.
+    pub fn new(lp: &Loop) -> Result<Tcp> {
+        unsafe {
+            let uv_loop = lp.checked_raw()?;
+            let raw: *mut uv_tcp_t = handle::alloc(libuv_sys::UV_TCP);
+            if let Err(err) = check(libuv_sys::uv_tcp_init(uv_loop, raw)) {
+                handle::free(raw);
+                return Err(err);
+            }
+            stream::attach(raw);
+            Ok(Tcp {
+                raw,
+                lp: lp.clone(),
+            })
+        }
+    }
+
+    /// Wraps an existing socket, which is switched to non-blocking mode.
+    pub fn open(lp: &Loop, sock: uv_os_sock_t) -> Result<Tcp> {
+        let tcp = Tcp::new(lp)?;
+        unsafe {
+            check(libuv_sys::uv_tcp_open(tcp.raw, sock))?;
+        }
+        Ok(tcp)
+    }
+
+    /// Returns the underlying `uv_tcp_t`.
+    pub fn as_raw(&self) -> *mut uv_tcp_t {
+        self.raw
+    }
+
+    /// Returns the loop this socket belongs to.
+    pub fn get_loop(&self) -> &Loop {
+        &self.lp
+    }
+
+    /// Returns the address the socket is bound to.
+    pub fn sockname(&self) -> Result<SocketAddr> {
+        addr::fetch(|name, len| unsafe { libuv_sys::uv_tcp_getsockname(self.raw, name, len) })
+    }
+
+    /// Returns the address of the socket's peer.
+    pub fn peername(&self) -> Result<SocketAddr> {
+        addr::fetch(|name, len| unsafe { libuv_sys::uv_tcp_getpeername(self.raw, name, len) })
+    }
+
+    /// Starts accepting connections.  `cb` receives each accepted socket, or the error which
+    /// prevented a connection from being accepted.
+    pub fn listen<F>(&self, backlog: i32, mut cb: F) -> Result<()>
+        where F: FnMut(Result<Tcp>) + 'static
+    {
+        let raw = self.raw;
+        unsafe {
+            stream::listen(raw as *mut uv_stream_t,
+                           backlog,
+                           Box::new(move |status| cb(status.and_then(|()| accept(raw)))))
+        }
+    }
+
+    /// Enables or disables Nagle's algorithm.
+    pub fn set_nodelay(&self, enable: bool) -> Result<()> {
+        unsafe { check(libuv_sys::uv_tcp_nodelay(self.raw, enable as c_int)).map(|_| ()) }
+    }
+
+    /// Enables TCP keep-alive with the given initial delay in seconds, or disables it.
+    pub fn set_keepalive(&self, delay: Option<u32>) -> Result<()> {
+        let (enable, delay) = match delay {
+            Some(delay) => (1, delay as c_uint),
+            None => (0, 0),
+        };
+        unsafe { check(libuv_sys::uv_tcp_keepalive(self.raw, enable, delay)).map(|_| ()) }
+    }
+
+    /// Controls whether the operating system queues simultaneous accepts on a listening socket.
+    /// Only has an effect on Windows.
+    pub fn set_simultaneous_accepts(&self, enable: bool) -> Result<()> {
+        unsafe {
+            check(libuv_sys::uv_tcp_simultaneous_accepts(self.raw, enable as c_int)).map(|_| ())
+        }
+    }
+}
+
+// Only called from the connection callback, while the listener is necessarily still open.
+unsafe fn accept(server: *mut uv_tcp_t) -> Result<Tcp> {
+    let lp = Loop::from_raw((*server).loop_);
+    let client = Tcp::new(&lp)?;
+    check(libuv_sys::uv_accept(server as *mut uv_stream_t, client.raw as *mut uv_stream_t))?;
+    Ok(client)
+}
+
+impl Sealed for Tcp {}
+
+impl Stream for Tcp {
+    fn as_raw_stream(&self) -> *mut uv_stream_t {
+        self.raw as *mut uv_stream_t
+    }
+}
+
+impl Drop for Tcp {
+    fn drop(&mut self) {
+        unsafe { stream::close(self.raw) }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use error::Error;
+    use event_loop::RunMode;
+    use std::cell::RefCell;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::os::unix::io::IntoRawFd;
+    use std::rc::Rc;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+    use stream::{StreamTimeouts, WriteBuf};
+    use timer::Timer;
+
+    #[test]
+    fn open_existing_socket() {
+        let lp = Loop::new().unwrap();
+        let sock = TcpListener::bind("127.0.0.1:0").unwrap().into_raw_fd();
+        let tcp = Tcp::open(&lp, sock).unwrap();
+        tcp.set_nodelay(true).unwrap();
+        drop(tcp);
+        assert!(!lp.run(RunMode::Default));
+    }
+
+    #[test]
+    fn connect_to_listener() {
+        let lp = Loop::new().unwrap();
+        let listener = Rc::new(RefCell::new(Some(Tcp::bind(&lp, &"127.0.0.1:0".parse().unwrap())
+            .unwrap())));
+        let addr = listener.borrow().as_ref().unwrap().sockname().unwrap();
+        assert!(addr.port() != 0);
+
+        let accepted = Rc::new(RefCell::new(None));
+        let accepted2 = accepted.clone();
+        let listener2 = listener.clone();
+        listener.borrow().as_ref().unwrap().listen(1, move |conn| {
+            *accepted2.borrow_mut() = Some(conn.unwrap().peername().unwrap());
+            listener2.borrow_mut().take();
+        }).unwrap();
+
+        let connected = Rc::new(RefCell::new(None));
+        let connected2 = connected.clone();
+        Tcp::connect(&lp, &addr, move |conn| {
+                *connected2.borrow_mut() = Some(conn.unwrap().sockname().unwrap());
+            })
+            .unwrap();
+
+        lp.run(RunMode::Default);
+        assert_eq!(*accepted.borrow(), *connected.borrow());
+        assert!(accepted.borrow().is_some());
+    }
+
+    // Connects a client to a listener and hands the accepted socket to `accepted`, keeping the
+    // client open until `client` is cleared.
+    fn accept_one<F>(lp: &Loop, client: Rc<RefCell<Option<Tcp>>>, mut accepted: F)
+        where F: FnMut(Tcp) + 'static
+    {
+        let listener = Rc::new(RefCell::new(Some(Tcp::bind(lp, &"127.0.0.1:0".parse().unwrap())
+            .unwrap())));
+        let addr = listener.borrow().as_ref().unwrap().sockname().unwrap();
+        let listener2 = listener.clone();
+        listener.borrow().as_ref().unwrap().listen(1, move |conn| {
+            accepted(conn.unwrap());
+            listener2.borrow_mut().take();
+        }).unwrap();
+        Tcp::connect(lp, &addr, move |conn| *client.borrow_mut() = Some(conn.unwrap())).unwrap();
+    }
+
+    #[test]
+    fn read_timeout() {
+        let lp = Loop::new().unwrap();
+        let client = Rc::new(RefCell::new(None));
+        let server = Rc::new(RefCell::new(None));
+        let result = Rc::new(RefCell::new(None));
+        let (client2, server2, result2) = (client.clone(), server.clone(), result.clone());
+        accept_one(&lp, client.clone(), move |conn| {
+            conn.set_timeouts(StreamTimeouts::new().read(Duration::from_millis(10))).unwrap();
+            let (client, server, result) = (client2.clone(), server2.clone(), result2.clone());
+            conn.read_start(move |data| {
+                    *result.borrow_mut() = Some(data.map(|data| data.len()));
+                    client.borrow_mut().take();
+                    server.borrow_mut().take();
+                })
+                .unwrap();
+            *server2.borrow_mut() = Some(conn);
+        });
+        lp.run(RunMode::Default);
+        assert_eq!(*result.borrow(), Some(Err(Error::ETIMEDOUT)));
+    }
+
+    #[test]
+    fn no_read_timeout_after_drop() {
+        let lp = Loop::new().unwrap();
+        let client = Rc::new(RefCell::new(None));
+        let server = Rc::new(RefCell::new(None));
+        let result = Rc::new(RefCell::new(None));
+        let (server2, result2) = (server.clone(), result.clone());
+        accept_one(&lp, client.clone(), move |conn| {
+            conn.set_timeouts(StreamTimeouts::new().read(Duration::from_millis(10))).unwrap();
+            let result = result2.clone();
+            conn.read_start(move |data| *result.borrow_mut() = Some(data.map(|data| data.len())))
+                .unwrap();
+            *server2.borrow_mut() = Some(conn);
+        });
+        while server.borrow().is_none() || client.borrow().is_none() {
+            lp.run(RunMode::Once);
+        }
+        // The deadline has passed by the next iteration, which runs timers before closing.
+        thread::sleep(Duration::from_millis(20));
+        server.borrow_mut().take();
+        client.borrow_mut().take();
+        lp.run(RunMode::Default);
+        assert_eq!(*result.borrow(), None);
+    }
+
+    #[test]
+    fn no_read_timeout_after_eof() {
+        let lp = Loop::new().unwrap();
+        let client = Rc::new(RefCell::new(None));
+        let server = Rc::new(RefCell::new(None));
+        let results = Rc::new(RefCell::new(Vec::new()));
+        let (server2, results2) = (server.clone(), results.clone());
+        accept_one(&lp, client.clone(), move |conn| {
+            conn.set_timeouts(StreamTimeouts::new().read(Duration::from_millis(10))).unwrap();
+            let results = results2.clone();
+            conn.read_start(move |data| results.borrow_mut().push(data.map(|data| data.len())))
+                .unwrap();
+            *server2.borrow_mut() = Some(conn);
+        });
+        while server.borrow().is_none() || client.borrow().is_none() {
+            lp.run(RunMode::Once);
+        }
+        // The client half-closes, and the server waits well past its read deadline.
+        client.borrow().as_ref().unwrap().shutdown(|res| res.unwrap()).unwrap();
+        let timer = Timer::new(&lp).unwrap();
+        timer.start(Duration::from_millis(50), Duration::from_secs(0), || ()).unwrap();
+        lp.run(RunMode::Default);
+        assert_eq!(*results.borrow(), [Err(Error::EOF)]);
+        server.borrow_mut().take();
+        client.borrow_mut().take();
+        lp.run(RunMode::Default);
+    }
+
+    #[test]
+    fn close_on_idle() {
+        let lp = Loop::new().unwrap();
+        let client = Rc::new(RefCell::new(None));
+        let server = Rc::new(RefCell::new(None));
+        let server2 = server.clone();
+        accept_one(&lp, client.clone(), move |conn| {
+            conn.set_timeouts(StreamTimeouts::new()
+                    .idle(Duration::from_millis(10))
+                    .close_on_idle(true))
+                .unwrap();
+            *server2.borrow_mut() = Some(conn);
+        });
+        while client.borrow().is_none() {
+            lp.run(RunMode::Once);
+        }
+
+        // The server closes its end, which the client sees as the end of the stream.
+        let result = Rc::new(RefCell::new(None));
+        let (client2, result2) = (client.clone(), result.clone());
+        client.borrow().as_ref().unwrap().read_start(move |data| {
+                *result2.borrow_mut() = Some(data.map(|data| data.len()));
+                client2.borrow_mut().take();
+            })
+            .unwrap();
+        lp.run(RunMode::Default);
+        assert_eq!(*result.borrow(), Some(Err(Error::EOF)));
+        // Dropping a stream after a timeout closed it frees it without closing it again.
+        server.borrow_mut().take();
+        assert!(!lp.run(RunMode::Default));
+    }
+
+    #[test]
+    fn write_shared_buffers() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let lp = Loop::new().unwrap();
+        let payload: Arc<[u8]> = Arc::from(&b"shared "[..]);
+        let payload2 = payload.clone();
+        let client = Rc::new(RefCell::new(None));
+        let client2 = client.clone();
+        Tcp::connect(&lp, &listener.local_addr().unwrap(), move |conn| {
+                let conn = conn.unwrap();
+                let bufs: Vec<Box<dyn WriteBuf>> =
+                    vec![Box::new(payload2), Box::new(b"and owned".to_vec())];
+                let client = client2.clone();
+                conn.write_vectored(bufs, move |result| {
+                        result.unwrap();
+                        client.borrow_mut().take();
+                    })
+                    .unwrap();
+                *client2.borrow_mut() = Some(conn);
+            })
+            .unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+        lp.run(RunMode::Default);
+        assert_eq!(Arc::strong_count(&payload), 1);
+        let mut received = String::new();
+        peer.read_to_string(&mut received).unwrap();
+        assert_eq!(received, "shared and owned");
+    }
+}
@@ -0,0 +1,119 @@
+//! The clocks libuv keeps time with.
+//!
+//! A loop caches the time, in milliseconds, at the start of each iteration and measures its
+//! timers against that cached value.  `LoopTime` is a reading of that clock, so deadlines built
+//! from it match when timers fire.  `hrtime` reads a high-resolution monotonic clock with the
+//! same origin.  Loops may read a cheaper, coarser clock than `hrtime` does; on Linux that is
+//! `CLOCK_MONOTONIC_COARSE` whenever its resolution is a millisecond or better, which lags
+//! `CLOCK_MONOTONIC` by up to a tick.
+
+use std::ops::{Add, AddAssign, Sub};
+use std::time::Duration;
+use sys_info;
+use timer;
+
+/// Returns the current high-resolution monotonic time.  It is relative to an arbitrary time in
+/// the past, so only differences between readings are meaningful.
+pub fn hrtime() -> Duration {
+    Duration::from_nanos(sys_info::hrtime())
+}
+
+/// A reading of a loop's cached clock, as returned by `Loop::now`.  Readings of different loops
+/// come from the same clock and may be compared.
+#[derive(Clone,Copy,Debug,Default,PartialEq,Eq,PartialOrd,Ord,Hash)]
+pub struct LoopTime(u64);
+
+impl LoopTime {
+    /// Returns the time `millis` milliseconds after the clock's origin.
+    pub fn from_millis(millis: u64) -> LoopTime {
+        LoopTime(millis)
+    }
+
+    /// Converts an `hrtime` reading, truncating it to the millisecond.  Since loops may read a
+    /// coarser clock, the result can be a little ahead of `Loop::now` taken at the same moment.
+    pub fn from_hrtime(time: Duration) -> LoopTime {
+        LoopTime(time.as_millis() as u64)
+    }
+
+    /// Returns the milliseconds since the clock's origin.
+    pub fn as_millis(self) -> u64 {
+        self.0
+    }
+
+    /// Returns the time elapsed from `earlier` to this time, or zero if `earlier` is later.
+    pub fn duration_since(self, earlier: LoopTime) -> Duration {
+        Duration::from_millis(self.0.saturating_sub(earlier.0))
+    }
+
+    /// Adds `duration`, rounded up to whole milliseconds as timers round it, unless that
+    /// overflows.
+    pub fn checked_add(self, duration: Duration) -> Option<LoopTime> {
+        self.0.checked_add(timer::millis(duration)).map(LoopTime)
+    }
+}
+
+impl Add<Duration> for LoopTime {
+    type Output = LoopTime;
+
+    /// Rounds `duration` up to whole milliseconds, so that a deadline computed for a timer
+    /// matches when the timer fires.
+    ///
+    /// # Panics
+    ///
+    /// Panics on overflow.
+    fn add(self, duration: Duration) -> LoopTime {
+        self.checked_add(duration).expect("overflow when adding a duration to a loop time")
+    }
+}
+
+impl AddAssign<Duration> for LoopTime {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<LoopTime> for LoopTime {
+    type Output = Duration;
+
+    /// Returns the time elapsed, saturating at zero like `duration_since`.
+    fn sub(self, earlier: LoopTime) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use event_loop::{Loop, RunMode};
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use timer::Timer;
+
+    #[test]
+    fn arithmetic_matches_timers() {
+        let start = LoopTime::from_millis(100);
+        assert_eq!(start + Duration::from_micros(1500), LoopTime::from_millis(102));
+        assert_eq!(LoopTime::from_millis(102) - start, Duration::from_millis(2));
+        assert_eq!(start - LoopTime::from_millis(102), Duration::from_secs(0));
+        assert_eq!(LoopTime::from_millis(u64::MAX).checked_add(Duration::from_millis(1)), None);
+        assert_eq!(LoopTime::from_hrtime(Duration::new(1, 999_999)).as_millis(), 1000);
+    }
+
+    #[test]
+    fn timers_fire_at_deadline() {
+        let lp = Loop::new().unwrap();
+        lp.update_time();
+        let deadline = lp.now() + Duration::from_millis(3);
+
+        let timer = Timer::new(&lp).unwrap();
+        let fired = Rc::new(Cell::new(None));
+        let fired2 = fired.clone();
+        let lp2 = lp.clone();
+        timer.start(Duration::from_millis(3), Duration::from_secs(0), move || {
+                fired2.set(Some(lp2.now()));
+            })
+            .unwrap();
+        lp.run(RunMode::Default);
+        assert!(fired.get().unwrap() >= deadline);
+    }
+}
@@ -0,0 +1,148 @@
+//! Timers.
+
+use error::{check, Result};
+use event_loop::Loop;
+use handle::{self, CallbackCell};
+use libc::c_void;
+use libuv_sys::{self, uv_timer_t};
+use std::time::Duration;
+
+type TimerCallback = Box<dyn FnMut()>;
+
+// libuv counts in whole milliseconds; round up so that a timer never fires early.
+pub(crate) fn millis(duration: Duration) -> u64 {
+    duration.as_nanos().div_ceil(1_000_000) as u64
+}
+
+/// Calls a function after a timeout, and optionally at a fixed interval after that.
+pub struct Timer {
+    raw: *mut uv_timer_t,
+    lp: Loop,
+}
+
+impl Timer {
+    /// Creates a timer which is not yet started.
+    pub fn new(lp: &Loop) -> Result<Timer> {
+        unsafe {
+            let uv_loop = lp.checked_raw()?;
+            let raw: *mut uv_timer_t = handle::alloc(libuv_sys::UV_TIMER);
+            if let Err(err) = check(libuv_sys::uv_timer_init(uv_loop, raw)) {
+                handle::free(raw);
+                return Err(err);
+            }
+            let cb: Box<CallbackCell<TimerCallback>> = Box::new(CallbackCell::new());
+            (*raw).data = Box::into_raw(cb) as *mut c_void;
+            Ok(Timer {
+                raw,
+                lp: lp.clone(),
+            })
+        }
+    }
+
+    /// Returns the underlying `uv_timer_t`.
+    pub fn as_raw(&self) -> *mut uv_timer_t {
+        self.raw
+    }
+
+    /// Returns the loop this timer belongs to.
+    pub fn get_loop(&self) -> &Loop {
+        &self.lp
+    }
+
+    /// Calls `cb` after `timeout`, and then every `repeat` unless it is zero, replacing any
+    /// callback set before.  Times are measured from the loop's cached time and rounded up to
+    /// whole milliseconds.
+    pub fn start<F>(&self, timeout: Duration, repeat: Duration, cb: F) -> Result<()>
+        where F: FnMut() + 'static
+    {
+        unsafe {
+            handle::ensure_open(self.raw)?;
+            let cell = self.callback();
+            cell.set(Box::new(cb));
+            let rc = libuv_sys::uv_timer_start(self.raw, timer_cb, millis(timeout), millis(repeat));
+            if let Err(err) = check(rc) {
+                cell.clear();
+                return Err(err);
+            }
+            Ok(())
+        }
+    }
+
+    /// Stops the timer and drops the callback.
+    pub fn stop(&self) -> Result<()> {
+        unsafe {
+            self.callback().clear();
+            check(libuv_sys::uv_timer_stop(self.raw)).map(|_| ())
+        }
+    }
+
+    /// Restarts a repeating timer so that it next fires one `repeat` interval from now.  Fails
+    /// with `EINVAL` if the timer was never started.
+    pub fn again(&self) -> Result<()> {
+        unsafe {
+            handle::ensure_open(self.raw)?;
+            check(libuv_sys::uv_timer_again(self.raw)).map(|_| ())
+        }
+    }
+
+    /// Changes the repeat interval, taking effect the next time the timer fires.
+    pub fn set_repeat(&self, repeat: Duration) {
+        unsafe { libuv_sys::uv_timer_set_repeat(self.raw, millis(repeat)) }
+    }
+
+    /// Returns the repeat interval.
+    pub fn repeat(&self) -> Duration {
+        unsafe { Duration::from_millis(libuv_sys::uv_timer_get_repeat(self.raw)) }
+    }
+
+    unsafe fn callback(&self) -> &CallbackCell<TimerCallback> {
+        &*((*self.raw).data as *const CallbackCell<TimerCallback>)
+    }
+}
+
+extern "C" fn timer_cb(raw: *mut uv_timer_t) {
+    unsafe {
+        let cell = &*((*raw).data as *const CallbackCell<TimerCallback>);
+        cell.with(|cb| cb());
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        unsafe { handle::close::<_, CallbackCell<TimerCallback>>(self.raw) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use event_loop::RunMode;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    #[test]
+    fn rounds_up_to_milliseconds() {
+        assert_eq!(millis(Duration::from_millis(5)), 5);
+        assert_eq!(millis(Duration::new(1, 1)), 1001);
+        assert_eq!(millis(Duration::from_secs(0)), 0);
+    }
+
+    #[test]
+    fn repeats_until_stopped() {
+        let lp = Loop::new().unwrap();
+        let timer = Rc::new(Timer::new(&lp).unwrap());
+        let fired = Rc::new(Cell::new(0));
+        let fired2 = fired.clone();
+        let timer2 = timer.clone();
+        timer.start(Duration::from_millis(1), Duration::from_millis(1), move || {
+                fired2.set(fired2.get() + 1);
+                if fired2.get() == 3 {
+                    timer2.stop().unwrap();
+                }
+            })
+            .unwrap();
+        assert_eq!(timer.repeat(), Duration::from_millis(1));
+        lp.run(RunMode::Default);
+        assert_eq!(fired.get(), 3);
+    }
+}
@@ -0,0 +1,449 @@
+//! Large numbers of timeouts on a single timer.
+//!
+//! A `TimerWheel` keeps its timeouts in a hierarchical timing wheel: four levels of 64 slots,
+//! where each slot of a level spans a whole turn of the level below.  A timeout goes into the
+//! lowest level whose range reaches its deadline and moves down a level each time its slot comes
+//! round, so inserting, resetting and cancelling take constant time however many timeouts there
+//! are.  A single `uv_timer_t` ticks once per resolution while any timeout is pending.
+
+use error::{Error, Result};
+use event_loop::Loop;
+use std::cell::{Cell, RefCell};
+use std::mem;
+use std::rc::{Rc, Weak};
+use std::time::Duration;
+use timer::{self, Timer};
+
+const LEVEL_BITS: u32 = 6;
+const SLOTS: usize = 1 << LEVEL_BITS;
+const LEVELS: usize = 4;
+const NONE: usize = usize::MAX;
+
+type TimeoutCallback = Box<dyn FnOnce()>;
+
+/// Identifies a timeout in a `TimerWheel`.  Keys are never reused, so a stale key is harmless.
+#[derive(Clone,Copy,Debug,PartialEq,Eq,Hash)]
+pub struct TimeoutKey {
+    index: usize,
+    generation: u64,
+}
+
+struct Entry {
+    generation: u64,
+    // The tick the timeout is due at.
+    deadline: u64,
+    // `None` once the entry is free.
+    cb: Option<TimeoutCallback>,
+    // The bucket's list links, or `NONE` when the entry is in no bucket.
+    bucket: usize,
+    prev: usize,
+    next: usize,
+}
+
+struct Wheel {
+    entries: Vec<Entry>,
+    free: Vec<usize>,
+    // The first entry of each bucket, level by level.
+    heads: [usize; SLOTS * LEVELS],
+    // The last tick which has been processed.
+    elapsed: u64,
+    len: usize,
+}
+
+impl Wheel {
+    fn new() -> Wheel {
+        Wheel {
+            entries: Vec::new(),
+            free: Vec::new(),
+            heads: [NONE; SLOTS * LEVELS],
+            elapsed: 0,
+            len: 0,
+        }
+    }
+
+    fn entry(&self, key: TimeoutKey) -> Option<usize> {
+        match self.entries.get(key.index) {
+            Some(entry) if entry.generation == key.generation && entry.cb.is_some() => {
+                Some(key.index)
+            }
+            _ => None,
+        }
+    }
+
+    // The bucket for `deadline`: the level is that of the highest group of bits in which it
+    // differs from `elapsed`.  Deadlines past the top level's range wrap around it and are
+    // placed again when their slot comes round.
+    fn bucket(&self, deadline: u64) -> usize {
+        let diff = self.elapsed ^ deadline;
+        let level = if diff == 0 {
+            0
+        } else {
+            ((63 - diff.leading_zeros()) / LEVEL_BITS) as usize
+        };
+        let level = level.min(LEVELS - 1);
+        let slot = (deadline >> (level as u32 * LEVEL_BITS)) as usize % SLOTS;
+        level * SLOTS + slot
+    }
+
+    fn link(&mut self, index: usize) {
+        let bucket = self.bucket(self.entries[index].deadline);
+        let head = self.heads[bucket];
+        if head != NONE {
+            self.entries[head].prev = index;
+        }
+        let entry = &mut self.entries[index];
+        entry.bucket = bucket;
+        entry.prev = NONE;
+        entry.next = head;
+        self.heads[bucket] = index;
+    }
+
+    fn unlink(&mut self, index: usize) {
+        let (bucket, prev, next) = {
+            let entry = &mut self.entries[index];
+            let links = (entry.bucket, entry.prev, entry.next);
+            entry.bucket = NONE;
+            links
+        };
+        if bucket == NONE {
+            return;
+        }
+        if prev == NONE {
+            self.heads[bucket] = next;
+        } else {
+            self.entries[prev].next = next;
+        }
+        if next != NONE {
+            self.entries[next].prev = prev;
+        }
+    }
+
+    // Removes every entry from a bucket, returning their indices.
+    fn drain(&mut self, bucket: usize) -> Vec<usize> {
+        let mut indices = Vec::new();
+        let mut index = mem::replace(&mut self.heads[bucket], NONE);
+        while index != NONE {
+            indices.push(index);
+            let entry = &mut self.entries[index];
+            entry.bucket = NONE;
+            index = entry.next;
+        }
+        indices
+    }
+
+    fn insert(&mut self, deadline: u64, cb: TimeoutCallback) -> TimeoutKey {
+        let deadline = deadline.max(self.elapsed + 1);
+        let index = match self.free.pop() {
+            Some(index) => {
+                let entry = &mut self.entries[index];
+                entry.deadline = deadline;
+                entry.cb = Some(cb);
+                index
+            }
+            None => {
+                self.entries.push(Entry {
+                    generation: 0,
+                    deadline,
+                    cb: Some(cb),
+                    bucket: NONE,
+                    prev: NONE,
+                    next: NONE,
+                });
+                self.entries.len() - 1
+            }
+        };
+        self.link(index);
+        self.len += 1;
+        TimeoutKey {
+            index,
+            generation: self.entries[index].generation,
+        }
+    }
+
+    fn reset(&mut self, key: TimeoutKey, deadline: u64) -> bool {
+        match self.entry(key) {
+            Some(index) => {
+                self.unlink(index);
+                self.entries[index].deadline = deadline.max(self.elapsed + 1);
+                self.link(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Frees the entry for `key`, returning its callback.
+    fn remove(&mut self, key: TimeoutKey) -> Option<TimeoutCallback> {
+        let index = self.entry(key)?;
+        self.unlink(index);
+        let entry = &mut self.entries[index];
+        entry.generation += 1;
+        self.free.push(index);
+        self.len -= 1;
+        entry.cb.take()
+    }
+
+    // Frees the entry for a key returned by `tick` and returns its callback, unless an earlier
+    // callback of the same tick reset the timeout to a later deadline.
+    fn take_due(&mut self, key: TimeoutKey) -> Option<TimeoutCallback> {
+        let index = self.entry(key)?;
+        if self.entries[index].deadline > self.elapsed {
+            return None;
+        }
+        self.remove(key)
+    }
+
+    // Processes the next tick: moves entries down from the levels whose slots come round, and
+    // returns the keys of the timeouts now due.  They stay in the wheel, in no bucket, until
+    // removed, so that a callback can still cancel one which is due in the same tick.
+    fn tick(&mut self) -> Vec<TimeoutKey> {
+        self.elapsed += 1;
+        let now = self.elapsed;
+        for level in (1..LEVELS).rev() {
+            let shift = level as u32 * LEVEL_BITS;
+            if now & ((1 << shift) - 1) == 0 {
+                let slot = (now >> shift) as usize % SLOTS;
+                for index in self.drain(level * SLOTS + slot) {
+                    self.link(index);
+                }
+            }
+        }
+        let mut due = Vec::new();
+        for index in self.drain(now as usize % SLOTS) {
+            let entry = &self.entries[index];
+            if entry.deadline <= now {
+                due.push(TimeoutKey {
+                    index,
+                    generation: entry.generation,
+                });
+            } else {
+                self.link(index);
+            }
+        }
+        due
+    }
+}
+
+struct Shared {
+    wheel: RefCell<Wheel>,
+    timer: Timer,
+    // Resolution in milliseconds.
+    resolution: u64,
+    // The loop time tick zero starts at.
+    origin: u64,
+    ticking: Cell<bool>,
+}
+
+impl Shared {
+    // The tick the loop's cached time falls in.
+    fn now(&self) -> u64 {
+        (self.timer.get_loop().now().as_millis() - self.origin) / self.resolution
+    }
+
+    // The first tick which starts at or after `timeout` from now, so that no timeout fires
+    // early.
+    fn deadline(&self, timeout: Duration) -> u64 {
+        let now = self.timer.get_loop().now().as_millis() - self.origin;
+        (now + timer::millis(timeout)).div_ceil(self.resolution)
+    }
+
+    fn start(shared: &Rc<Shared>) -> Result<()> {
+        if shared.ticking.get() {
+            return Ok(());
+        }
+        let weak: Weak<Shared> = Rc::downgrade(shared);
+        let resolution = Duration::from_millis(shared.resolution);
+        shared.timer.start(resolution, resolution, move || {
+                if let Some(shared) = weak.upgrade() {
+                    shared.advance();
+                }
+            })?;
+        shared.ticking.set(true);
+        Ok(())
+    }
+
+    fn advance(&self) {
+        let now = self.now();
+        loop {
+            let due = {
+                let mut wheel = self.wheel.borrow_mut();
+                if wheel.elapsed >= now {
+                    break;
+                }
+                wheel.tick()
+            };
+            for key in due {
+                let cb = self.wheel.borrow_mut().take_due(key);
+                if let Some(cb) = cb {
+                    cb();
+                }
+            }
+        }
+        if self.wheel.borrow().len == 0 && self.ticking.get() {
+            let _ = self.timer.stop();
+            self.ticking.set(false);
+        }
+    }
+}
+
+/// Schedules any number of one-shot timeouts on one timer.  Timeouts fire no earlier than asked,
+/// and up to one resolution later.  Dropping the wheel cancels every pending timeout.
+pub struct TimerWheel {
+    shared: Rc<Shared>,
+}
+
+impl TimerWheel {
+    /// Creates a wheel which checks for due timeouts every `resolution`, rounded up to whole
+    /// milliseconds.  Fails with `EINVAL` if `resolution` is zero.
+    pub fn new(lp: &Loop, resolution: Duration) -> Result<TimerWheel> {
+        let resolution = timer::millis(resolution);
+        if resolution == 0 {
+            return Err(Error::EINVAL);
+        }
+        Ok(TimerWheel {
+            shared: Rc::new(Shared {
+                wheel: RefCell::new(Wheel::new()),
+                timer: Timer::new(lp)?,
+                resolution,
+                origin: lp.now().as_millis(),
+                ticking: Cell::new(false),
+            }),
+        })
+    }
+
+    /// Calls `cb` once `timeout` has passed, unless the timeout is cancelled first.  Fails with
+    /// `EBADF` once the loop has been closed.
+    pub fn insert<F>(&self, timeout: Duration, cb: F) -> Result<TimeoutKey>
+        where F: FnOnce() + 'static
+    {
+        let deadline = self.shared.deadline(timeout);
+        let key = {
+            let mut wheel = self.shared.wheel.borrow_mut();
+            if wheel.len == 0 {
+                // Nothing is pending, so the ticks the timer skipped while stopped can be too.
+                wheel.elapsed = wheel.elapsed.max(self.shared.now());
+            }
+            wheel.insert(deadline, Box::new(cb))
+        };
+        if let Err(err) = Shared::start(&self.shared) {
+            self.shared.wheel.borrow_mut().remove(key);
+            return Err(err);
+        }
+        Ok(key)
+    }
+
+    /// Postpones a pending timeout so that it fires `timeout` from now.  Returns false if it
+    /// already fired or was cancelled.
+    pub fn reset(&self, key: TimeoutKey, timeout: Duration) -> bool {
+        let deadline = self.shared.deadline(timeout);
+        self.shared.wheel.borrow_mut().reset(key, deadline)
+    }
+
+    /// Cancels a pending timeout, dropping its callback.  Returns false if it already fired or
+    /// was cancelled.
+    pub fn cancel(&self, key: TimeoutKey) -> bool {
+        let cb = self.shared.wheel.borrow_mut().remove(key);
+        cb.is_some()
+    }
+
+    /// Returns the number of pending timeouts.
+    pub fn len(&self) -> usize {
+        self.shared.wheel.borrow().len
+    }
+
+    /// Returns true if no timeouts are pending.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the resolution.
+    pub fn resolution(&self) -> Duration {
+        Duration::from_millis(self.shared.resolution)
+    }
+
+    /// Returns the loop the wheel's timer belongs to.
+    pub fn get_loop(&self) -> &Loop {
+        self.shared.timer.get_loop()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use event_loop::RunMode;
+
+    #[test]
+    fn entries_fire_on_their_tick() {
+        let mut wheel = Wheel::new();
+        let deadlines = [1, 63, 64, 65, 4095, 4096, 4097, 262_143, 262_144, 300_000];
+        for &deadline in &deadlines {
+            wheel.insert(deadline, Box::new(|| {}));
+        }
+        let cancelled = wheel.insert(100, Box::new(|| {}));
+        assert!(wheel.remove(cancelled).is_some());
+        assert!(wheel.remove(cancelled).is_none());
+
+        let mut fired = Vec::new();
+        while wheel.len > 0 {
+            for key in wheel.tick() {
+                assert!(wheel.remove(key).is_some());
+                fired.push(wheel.elapsed);
+            }
+        }
+        assert_eq!(fired, deadlines);
+    }
+
+    #[test]
+    fn reset_during_tick_postpones() {
+        let mut wheel = Wheel::new();
+        let first = wheel.insert(1, Box::new(|| {}));
+        let second = wheel.insert(1, Box::new(|| {}));
+        let due = wheel.tick();
+        assert_eq!(due.len(), 2);
+        // As if the first callback reset the second timeout.
+        assert!(wheel.reset(second, 5));
+        assert!(wheel.take_due(first).is_some());
+        assert!(wheel.take_due(second).is_none());
+
+        let mut fired = Vec::new();
+        while wheel.len > 0 {
+            for key in wheel.tick() {
+                assert!(wheel.take_due(key).is_some());
+                fired.push(wheel.elapsed);
+            }
+        }
+        assert_eq!(fired, [5]);
+    }
+
+    #[test]
+    fn fires_cancels_and_resets() {
+        let lp = Loop::new().unwrap();
+        let wheel = TimerWheel::new(&lp, Duration::from_millis(1)).unwrap();
+        let fired = Rc::new(RefCell::new(Vec::new()));
+        let record = |name: &'static str| {
+            let fired = fired.clone();
+            move || fired.borrow_mut().push(name)
+        };
+        wheel.insert(Duration::from_millis(2), record("a")).unwrap();
+        let b = wheel.insert(Duration::from_millis(3), record("b")).unwrap();
+        let c = wheel.insert(Duration::from_millis(1), record("c")).unwrap();
+        assert_eq!(wheel.len(), 3);
+        assert!(wheel.cancel(b));
+        assert!(wheel.reset(c, Duration::from_millis(6)));
+        lp.run(RunMode::Default);
+
+        assert_eq!(*fired.borrow(), ["a", "c"]);
+        assert!(wheel.is_empty());
+        assert!(!wheel.reset(c, Duration::from_millis(1)));
+        assert_eq!(TimerWheel::new(&lp, Duration::from_secs(0)).err(), Some(Error::EINVAL));
+    }
+
+    #[test]
+    fn insert_fails_once_loop_closed() {
+        let lp = Loop::new().unwrap();
+        let wheel = TimerWheel::new(&lp, Duration::from_millis(1)).unwrap();
+        lp.close_all().unwrap();
+        assert_eq!(wheel.insert(Duration::from_millis(1), || ()).err(), Some(Error::EBADF));
+        assert!(wheel.is_empty());
+    }
+}
@@ -0,0 +1,208 @@
+//! Terminals.
+
+use error::{check, Result};
+use event_loop::Loop;
+use handle;
+use libc::c_int;
+use libuv_sys::{self, uv_file, uv_stream_t, uv_tty_mode_t, uv_tty_t};
+use sealed::Sealed;
+use signal::{self, Signal};
+use std::cell::RefCell;
+use std::ops::Deref;
+use stream::{self, Stream};
+
+/// How a terminal processes input.
+#[derive(Clone,Copy,Debug,PartialEq,Eq,Hash)]
+pub enum TtyMode {
+    /// Line-buffered input with echo, as the terminal starts out.
+    Normal,
+    /// Unbuffered input without echo or special handling of control characters.
+    Raw,
+    /// Binary-safe mode for IPC.  Only supported on Unix.
+    Io,
+}
+
+impl TtyMode {
+    fn to_raw(self) -> uv_tty_mode_t {
+        match self {
+            TtyMode::Normal => libuv_sys::UV_TTY_MODE_NORMAL,
+            TtyMode::Raw => libuv_sys::UV_TTY_MODE_RAW,
+            TtyMode::Io => libuv_sys::UV_TTY_MODE_IO,
+        }
+    }
+}
+
+/// A terminal.
+pub struct Tty {
+    raw: *mut uv_tty_t,
+    lp: Loop,
+    // The `SIGWINCH` watcher for `watch_resize`.  Its callback reads this terminal, so it must
+    // not outlive it.
+    resize: RefCell<Option<Signal>>,
+}
+
+impl Tty {
+    /// Opens the terminal on file descriptor `fd`.  `readable` must be set to read from it.
+    pub fn new(lp: &Loop, fd: uv_file, readable: bool) -> Result<Tty> {
+        unsafe {
+            let uv_loop = lp.checked_raw()?;
+            let raw: *mut uv_tty_t = handle::alloc(libuv_sys::UV_TTY);
+            let rc = libuv_sys::uv_tty_init(uv_loop, raw, fd, readable as c_int);
+            if let Err(err) = check(rc) {
+                handle::free(raw);
+                return Err(err);
+            }
+            stream::attach(raw);
+            Ok(Tty {
+                raw,
+                lp: lp.clone(),
+                resize: RefCell::new(None),
+            })
+        }
+    }
+
+    /// Returns true if `fd` refers to a terminal.
+    pub fn is_tty(fd: uv_file) -> bool {
+        unsafe { libuv_sys::uv_guess_handle(fd) == libuv_sys::UV_TTY }
+    }
+
+    /// Returns the underlying `uv_tty_t`.
+    pub fn as_raw(&self) -> *mut uv_tty_t {
+        self.raw
+    }
+
+    /// Returns the loop this terminal belongs to.
+    pub fn get_loop(&self) -> &Loop {
+        &self.lp
+    }
+
+    /// Switches the terminal to `mode`.
+    pub fn set_mode(&self, mode: TtyMode) -> Result<()> {
+        unsafe { check(libuv_sys::uv_tty_set_mode(self.raw, mode.to_raw())).map(|_| ()) }
+    }
+
+    /// Switches the terminal to `mode` until the returned guard is dropped, at which point it
+    /// returns to `TtyMode::Normal`.  The guard is dropped by a panic which unwinds past it
+    /// outside loop callbacks, but a panic inside a callback aborts the process without
+    /// unwinding; call `reset_mode` from a panic hook to cover that case too.
+    pub fn guard_mode(self, mode: TtyMode) -> Result<ModeGuard> {
+        self.set_mode(mode)?;
+        Ok(ModeGuard { tty: Some(self) })
+    }
+
+    /// Returns the width and height of the terminal's window, in characters.
+    pub fn winsize(&self) -> Result<(i32, i32)> {
+        unsafe { winsize(self.raw) }
+    }
+
+    /// Calls `cb` with the new window size whenever the terminal is resized, replacing any
+    /// callback set before.  This watches for `SIGWINCH`, which libuv emulates on Windows.
+    pub fn watch_resize<F>(&self, mut cb: F) -> Result<()>
+        where F: FnMut(Result<(i32, i32)>) + 'static
+    {
+        let mut resize = self.resize.borrow_mut();
+        if resize.is_none() {
+            *resize = Some(Signal::new(&self.lp)?);
+        }
+        let raw = self.raw;
+        resize.as_ref()
+            .unwrap()
+            .start(signal::SIGWINCH, move |_| cb(unsafe { winsize(raw) }))
+    }
+
+    /// Stops calling the callback set by `watch_resize`.
+    pub fn unwatch_resize(&self) {
+        self.resize.borrow_mut().take();
+    }
+}
+
+unsafe fn winsize(raw: *mut uv_tty_t) -> Result<(i32, i32)> {
+    let mut width = 0;
+    let mut height = 0;
+    check(libuv_sys::uv_tty_get_winsize(raw, &mut width, &mut height))?;
+    Ok((width, height))
+}
+
+impl Sealed for Tty {}
+
+impl Stream for Tty {
+    fn as_raw_stream(&self) -> *mut uv_stream_t {
+        self.raw as *mut uv_stream_t
+    }
+}
+
+impl Drop for Tty {
+    fn drop(&mut self) {
+        unsafe { stream::close(self.raw) }
+    }
+}
+
+/// A terminal switched to another mode by `Tty::guard_mode`.  Derefs to the `Tty`.
+pub struct ModeGuard {
+    tty: Option<Tty>,
+}
+
+impl ModeGuard {
+    /// Returns the terminal to `TtyMode::Normal` and gives it back.
+    pub fn into_inner(mut self) -> Result<Tty> {
+        let tty = self.tty.take().unwrap();
+        tty.set_mode(TtyMode::Normal)?;
+        Ok(tty)
+    }
+}
+
+impl Deref for ModeGuard {
+    type Target = Tty;
+
+    fn deref(&self) -> &Tty {
+        self.tty.as_ref().unwrap()
+    }
+}
+
+impl Drop for ModeGuard {
+    fn drop(&mut self) {
+        if let Some(ref tty) = self.tty {
+            let _ = tty.set_mode(TtyMode::Normal);
+        }
+    }
+}
+
+/// Restores the mode every terminal had before libuv first changed it.  Unlike the other
+/// functions in this module, this is async-signal-safe, so it can be called from a signal
+/// handler or panic hook as a last resort.
+pub fn reset_mode() -> Result<()> {
+    unsafe { check(libuv_sys::uv_tty_reset_mode()).map(|_| ()) }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use event_loop::RunMode;
+    use std::fs::OpenOptions;
+    use std::os::unix::io::{AsRawFd, IntoRawFd};
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn pipe_is_not_a_tty() {
+        let (a, _b) = UnixStream::pair().unwrap();
+        assert!(!Tty::is_tty(a.as_raw_fd()));
+    }
+
+    // Only runs with a controlling terminal.
+    #[test]
+    fn raw_mode_guard() {
+        let file = match OpenOptions::new().read(true).write(true).open("/dev/tty") {
+            Ok(file) => file,
+            Err(_) => return,
+        };
+        let lp = Loop::new().unwrap();
+        let tty = Tty::new(&lp, file.into_raw_fd(), true).unwrap();
+        let (width, height) = tty.winsize().unwrap();
+        assert!(width > 0 && height > 0);
+        let guard = tty.guard_mode(TtyMode::Raw).unwrap();
+        guard.watch_resize(|_| ()).unwrap();
+        guard.unwatch_resize();
+        drop(guard.into_inner().unwrap());
+        lp.run(RunMode::Default);
+    }
+}
@@ -0,0 +1,69 @@
+//! UDP sockets.
+
+use error::{check, Result};
+use event_loop::Loop;
+use handle;
+use libc::c_int;
+use libuv_sys::{self, uv_os_sock_t, uv_udp_t};
+use sealed::Sealed;
+
+/// A UDP socket.
+pub struct Udp {
+    raw: *mut uv_udp_t,
+    lp: Loop,
+}
+
+impl Udp {
+    /// Creates a socket which is not yet bound.
+    pub fn new(lp: &Loop) -> Result<Udp> {
+        unsafe {
+            let uv_loop = lp.checked_raw()?;
+            let raw: *mut uv_udp_t = handle::alloc(libuv_sys::UV_UDP);
+            if let Err(err) = check(libuv_sys::uv_udp_init(uv_loop, raw)) {
+                handle::free(raw);
+                return Err(err);
+            }
+            Ok(Udp {
+                raw,
+                lp: lp.clone(),
+            })
+        }
+    }
+
+    /// Wraps an existing socket, which is switched to non-blocking mode.
+    pub fn open(lp: &Loop, sock: uv_os_sock_t) -> Result<Udp> {
+        let udp = Udp::new(lp)?;
+        unsafe {
+            check(libuv_sys::uv_udp_open(udp.raw, sock))?;
+        }
+        Ok(udp)
+    }
+
+    /// Returns the underlying `uv_udp_t`.
+    pub fn as_raw(&self) -> *mut uv_udp_t {
+        self.raw
+    }
+
+    /// Returns the loop this socket belongs to.
+    pub fn get_loop(&self) -> &Loop {
+        &self.lp
+    }
+
+    /// Enables or disables sending to broadcast addresses.
+    pub fn set_broadcast(&self, enable: bool) -> Result<()> {
+        unsafe { check(libuv_sys::uv_udp_set_broadcast(self.raw, enable as c_int)).map(|_| ()) }
+    }
+
+    /// Sets the time-to-live of outgoing packets.
+    pub fn set_ttl(&self, ttl: u8) -> Result<()> {
+        unsafe { check(libuv_sys::uv_udp_set_ttl(self.raw, ttl as c_int)).map(|_| ()) }
+    }
+}
+
+impl Sealed for Udp {}
+
+impl Drop for Udp {
+    fn drop(&mut self) {
+        unsafe { handle::close::<_, ()>(self.raw) }
+    }
+}
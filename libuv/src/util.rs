@@ -0,0 +1,102 @@
+// Conversions between Rust strings and paths and the C strings libuv expects.
+
+use error::{check, Error, Result};
+use libc::{c_char, c_int, size_t};
+use std::ffi::{CString, OsStr};
+use std::path::{Path, PathBuf};
+
+#[cfg(unix)]
+pub fn os_str_to_cstring(s: &OsStr) -> Result<CString> {
+    use std::os::unix::ffi::OsStrExt;
+    CString::new(s.as_bytes()).map_err(|_| Error::EINVAL)
+}
+
+// libuv converts strings from UTF-8 to UTF-16 internally on Windows.
+#[cfg(windows)]
+pub fn os_str_to_cstring(s: &OsStr) -> Result<CString> {
+    let s = s.to_str().ok_or(Error::EINVAL)?;
+    CString::new(s).map_err(|_| Error::EINVAL)
+}
+
+pub fn path_to_cstring(path: &Path) -> Result<CString> {
+    os_str_to_cstring(path.as_os_str())
+}
+
+#[cfg(unix)]
+pub fn bytes_to_path(bytes: Vec<u8>) -> PathBuf {
+    use std::ffi::OsString;
+    use std::os::unix::ffi::OsStringExt;
+    PathBuf::from(OsString::from_vec(bytes))
+}
+
+#[cfg(windows)]
+pub fn bytes_to_path(bytes: Vec<u8>) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+// Older versions of `uv_cwd` pass on `ERANGE` from `getcwd` without storing a size.
+#[cfg(unix)]
+fn too_small(rc: c_int) -> bool {
+    rc == Error::ENOBUFS.code() || rc == -::libc::ERANGE
+}
+
+#[cfg(windows)]
+fn too_small(rc: c_int) -> bool {
+    rc == Error::ENOBUFS.code()
+}
+
+/// Calls a libuv function which fills a caller-supplied buffer and takes its size by pointer.
+/// Such functions fail with `UV_ENOBUFS` and store the required size when the buffer is too
+/// small, and otherwise store the length of the result excluding the terminating NUL.
+pub fn fetch_bytes<F>(mut f: F) -> Result<Vec<u8>>
+    where F: FnMut(*mut c_char, *mut size_t) -> c_int
+{
+    let mut buf: Vec<u8> = Vec::with_capacity(256);
+    loop {
+        let mut size = buf.capacity() as size_t;
+        let rc = f(buf.as_mut_ptr() as *mut c_char, &mut size);
+        if too_small(rc) {
+            let wanted = (size as usize + 1).max(buf.capacity() * 2);
+            buf.reserve(wanted);
+            continue;
+        }
+        check(rc)?;
+        unsafe {
+            buf.set_len(size as usize);
+        }
+        return Ok(buf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libc::{c_char, size_t};
+    use std::ptr;
+
+    // Mimics a libuv getter returning `value`, including the UV_ENOBUFS protocol.
+    fn getter(value: &[u8], buf: *mut c_char, size: *mut size_t) -> c_int {
+        unsafe {
+            if *size <= value.len() as size_t {
+                *size = value.len() as size_t + 1;
+                return Error::ENOBUFS.code();
+            }
+            ptr::copy_nonoverlapping(value.as_ptr(), buf as *mut u8, value.len());
+            *buf.add(value.len()) = 0;
+            *size = value.len() as size_t;
+            0
+        }
+    }
+
+    #[test]
+    fn fetch_retries_on_enobufs() {
+        let long = vec![b'x'; 1000];
+        assert_eq!(fetch_bytes(|buf, size| getter(&long, buf, size)).unwrap(), long);
+        assert_eq!(fetch_bytes(|buf, size| getter(b"short", buf, size)).unwrap(), b"short");
+    }
+
+    #[test]
+    fn interior_nul_is_invalid() {
+        assert_eq!(path_to_cstring(Path::new("a\0b")), Err(Error::EINVAL));
+    }
+}
@@ -0,0 +1,323 @@
+//! Buffered writing with backpressure.
+//!
+//! `Stream::write` queues every buffer as a request of its own and accepts data however slowly
+//! the peer reads it.  A `BufferedWriter` instead writes what it can at once with
+//! `uv_try_write`, gathers the rest while a write is in progress, and sends everything gathered
+//! in a single `uv_write` once that write completes.  It tells producers to pause when more than
+//! a high-water mark is buffered, and calls back once the buffer has drained or a write has
+//! failed.
+
+use error::{Error, Result};
+use handle::CallbackCell;
+use std::cell::{Cell, RefCell};
+use std::rc::{Rc, Weak};
+use stream::{self, Stream};
+
+// Writes smaller than this are copied onto the end of the last buffer rather than queued as
+// buffers of their own.
+const COALESCE_LIMIT: usize = 1024;
+// The largest buffer small writes are coalesced into.
+const COALESCE_BUFFER: usize = 64 * 1024;
+
+type Callback = Box<dyn FnMut()>;
+type ErrorCallback = Box<dyn FnMut(Error)>;
+
+struct Shared<S> {
+    stream: S,
+    high_water: usize,
+    // Data waiting for the write in progress to complete.
+    pending: RefCell<Vec<Vec<u8>>>,
+    pending_len: Cell<usize>,
+    writing: Cell<bool>,
+    // Set once a write has returned false, until the buffer drains or a write fails.
+    paused: Cell<bool>,
+    error: Cell<Option<Error>>,
+    high_water_cb: CallbackCell<Callback>,
+    drain_cb: CallbackCell<Callback>,
+    error_cb: CallbackCell<ErrorCallback>,
+}
+
+impl<S: Stream + 'static> Shared<S> {
+    fn buffered(&self) -> usize {
+        self.pending_len.get() + self.stream.write_queue_size()
+    }
+
+    fn push(&self, data: Vec<u8>) {
+        let mut pending = self.pending.borrow_mut();
+        self.pending_len.set(self.pending_len.get() + data.len());
+        if data.len() < COALESCE_LIMIT {
+            if let Some(last) = pending.last_mut() {
+                if last.len() + data.len() <= COALESCE_BUFFER {
+                    last.extend_from_slice(&data);
+                    return;
+                }
+            }
+        }
+        pending.push(data);
+    }
+
+    // Sends everything pending in one write, unless a write is already in progress.
+    fn flush(this: &Rc<Shared<S>>) -> Result<()> {
+        if this.writing.get() || this.pending_len.get() == 0 {
+            return Ok(());
+        }
+        let data = this.pending.replace(Vec::new());
+        this.pending_len.set(0);
+        let weak = Rc::downgrade(this);
+        unsafe {
+            stream::queue_write(this.stream.as_raw_stream(),
+                                data,
+                                None,
+                                Box::new(move |result| Shared::written(weak, result)))?;
+        }
+        this.writing.set(true);
+        Ok(())
+    }
+
+    fn written(weak: Weak<Shared<S>>, result: Result<()>) {
+        let this = match weak.upgrade() {
+            Some(this) => this,
+            None => return,
+        };
+        this.writing.set(false);
+        let result = result.and_then(|()| Shared::flush(&this));
+        if let Err(err) = result {
+            // An earlier failure was reported already, and writes cancelled by it follow.
+            if this.error.get().is_none() {
+                this.fail(err);
+                this.error_cb.with(|cb| cb(err));
+            }
+            return;
+        }
+        if this.paused.get() && !this.writing.get() {
+            this.paused.set(false);
+            this.drain_cb.with(|cb| cb());
+        }
+    }
+
+    // Drops the data which can no longer be written.  Producers no longer need to wait.
+    fn fail(&self, err: Error) {
+        self.error.set(Some(err));
+        self.pending.borrow_mut().clear();
+        self.pending_len.set(0);
+        self.paused.set(false);
+    }
+}
+
+/// A stream wrapped with a write buffer.  Dropping the writer drops the stream, and with it any
+/// data still buffered.
+pub struct BufferedWriter<S: Stream> {
+    shared: Rc<Shared<S>>,
+}
+
+impl<S: Stream + 'static> BufferedWriter<S> {
+    /// Wraps `stream`, asking producers to pause once more than `high_water` bytes are
+    /// buffered.
+    pub fn new(stream: S, high_water: usize) -> BufferedWriter<S> {
+        BufferedWriter {
+            shared: Rc::new(Shared {
+                stream,
+                high_water,
+                pending: RefCell::new(Vec::new()),
+                pending_len: Cell::new(0),
+                writing: Cell::new(false),
+                paused: Cell::new(false),
+                error: Cell::new(None),
+                high_water_cb: CallbackCell::new(),
+                drain_cb: CallbackCell::new(),
+                error_cb: CallbackCell::new(),
+            }),
+        }
+    }
+
+    /// Writes `data`, or buffers it to be written once the stream can take it.  Returns false if
+    /// the producer should pause until the drain callback, because more than the high-water
+    /// mark is now buffered; the data is buffered anyway.  Fails with the error of an earlier
+    /// write, after which nothing more is written.
+    pub fn write(&self, data: Vec<u8>) -> Result<bool> {
+        let shared = &self.shared;
+        if let Some(err) = shared.error.get() {
+            return Err(err);
+        }
+        if data.is_empty() {
+            return Ok(!shared.paused.get());
+        }
+        let mut data = data;
+        if !shared.writing.get() && shared.pending_len.get() == 0 {
+            match shared.stream.try_write(&data) {
+                Ok(n) if n == data.len() => return Ok(!shared.paused.get()),
+                Ok(n) => {
+                    data.drain(..n);
+                }
+                Err(Error::EAGAIN) => {}
+                Err(err) => {
+                    shared.fail(err);
+                    return Err(err);
+                }
+            }
+        }
+        shared.push(data);
+        if let Err(err) = Shared::flush(shared) {
+            shared.fail(err);
+            return Err(err);
+        }
+        if shared.buffered() > shared.high_water && !shared.paused.replace(true) {
+            shared.high_water_cb.with(|cb| cb());
+        }
+        Ok(!shared.paused.get())
+    }
+
+    /// Sets the callback for when a write takes the buffered amount past the high-water mark.
+    pub fn on_high_water<F>(&self, cb: F)
+        where F: FnMut() + 'static
+    {
+        self.shared.high_water_cb.set(Box::new(cb));
+    }
+
+    /// Sets the callback for when everything buffered since the high-water mark was passed has
+    /// been written, and producers may resume.
+    pub fn on_drain<F>(&self, cb: F)
+        where F: FnMut() + 'static
+    {
+        self.shared.drain_cb.set(Box::new(cb));
+    }
+
+    /// Sets the callback for when a write fails after `write` has returned, in place of the
+    /// drain callback.  The buffered data is dropped, producers are no longer paused, and every
+    /// later `write` fails with the same error.
+    pub fn on_error<F>(&self, cb: F)
+        where F: FnMut(Error) + 'static
+    {
+        self.shared.error_cb.set(Box::new(cb));
+    }
+
+    /// Returns the number of bytes buffered here or queued in libuv and not yet written.
+    pub fn buffered(&self) -> usize {
+        self.shared.buffered()
+    }
+
+    /// Returns the high-water mark.
+    pub fn high_water(&self) -> usize {
+        self.shared.high_water
+    }
+
+    /// Returns true if producers should pause.
+    pub fn is_paused(&self) -> bool {
+        self.shared.paused.get()
+    }
+
+    /// Returns the stream, for reading from it.
+    pub fn get_ref(&self) -> &S {
+        &self.shared.stream
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use event_loop::{Loop, RunMode};
+    use std::io::Read;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use tcp::Tcp;
+
+    type Writer = Rc<RefCell<Option<BufferedWriter<Tcp>>>>;
+
+    // Connects a writer to a new listener, returning it with the accepted end.
+    fn connect(lp: &Loop) -> (Writer, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let writer = Rc::new(RefCell::new(None));
+        let writer2 = writer.clone();
+        Tcp::connect(lp, &addr, move |conn| {
+                *writer2.borrow_mut() = Some(BufferedWriter::new(conn.unwrap(), 64 * 1024));
+            })
+            .unwrap();
+        let (peer, _) = listener.accept().unwrap();
+        while writer.borrow().is_none() {
+            lp.run(RunMode::Once);
+        }
+        (writer, peer)
+    }
+
+    // Writes numbered chunks until the writer asks to pause, returning the bytes written.
+    fn fill(writer: &BufferedWriter<Tcp>) -> usize {
+        let mut written = 0;
+        for i in 0..100_000 {
+            written += 1000;
+            if !writer.write(vec![i as u8; 1000]).unwrap() {
+                break;
+            }
+        }
+        written
+    }
+
+    #[test]
+    fn pauses_until_drained() {
+        let lp = Loop::new().unwrap();
+        let (writer, mut peer) = connect(&lp);
+
+        // Nobody reads yet, so the socket buffers fill and the writer passes its high-water mark.
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let written = {
+            let writer = writer.borrow();
+            let writer = writer.as_ref().unwrap();
+            let events2 = events.clone();
+            writer.on_high_water(move || events2.borrow_mut().push("high water"));
+            let events2 = events.clone();
+            writer.on_drain(move || events2.borrow_mut().push("drain"));
+            let written = fill(writer);
+            assert!(writer.is_paused());
+            assert!(writer.buffered() > writer.high_water());
+            written
+        };
+        assert_eq!(*events.borrow(), ["high water"]);
+
+        let reader = thread::spawn(move || {
+            let mut buf = vec![0; written];
+            peer.read_exact(&mut buf).unwrap();
+            buf
+        });
+        while writer.borrow().as_ref().unwrap().is_paused() {
+            lp.run(RunMode::Once);
+        }
+        assert_eq!(*events.borrow(), ["high water", "drain"]);
+        assert_eq!(writer.borrow().as_ref().unwrap().buffered(), 0);
+        let buf = reader.join().unwrap();
+        for (i, chunk) in buf.chunks(1000).enumerate() {
+            assert!(chunk.iter().all(|&b| b == i as u8));
+        }
+        writer.borrow_mut().take();
+        lp.run(RunMode::Default);
+    }
+
+    #[test]
+    fn write_error_unpauses() {
+        let lp = Loop::new().unwrap();
+        let (writer, peer) = connect(&lp);
+        let error = Rc::new(Cell::new(None));
+        {
+            let writer = writer.borrow();
+            let writer = writer.as_ref().unwrap();
+            let error2 = error.clone();
+            writer.on_error(move |err| error2.set(Some(err)));
+            fill(writer);
+            assert!(writer.is_paused());
+        }
+        // Closing with unread data resets the connection.
+        drop(peer);
+        while error.get().is_none() {
+            lp.run(RunMode::Once);
+        }
+        let err = error.get().unwrap();
+        {
+            let writer = writer.borrow();
+            let writer = writer.as_ref().unwrap();
+            assert!(!writer.is_paused());
+            assert_eq!(writer.buffered(), 0);
+            assert_eq!(writer.write(vec![0]), Err(err));
+        }
+        writer.borrow_mut().take();
+        lp.run(RunMode::Default);
+    }
+}